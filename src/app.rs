@@ -1,28 +1,95 @@
 use std::env;
-use std::io;
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::time::Duration;
 
-use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::event::{DisableMouseCapture, EnableMouseCapture};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::execute;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
 };
 
-use crate::editor::Editor;
+use crate::editor::{Editor, NormalInputResult};
+use crate::input;
 use crate::keymap::{Action, Mode};
 use crate::ui::Ui;
 
+/// Runs one ex command line. Returns `true` when the editor should quit.
+fn run_ex_command<W: Write>(
+    ed: &mut Editor,
+    ui: &mut Ui,
+    out: &mut W,
+    cmd: &str,
+) -> io::Result<bool> {
+    if !cmd.trim().is_empty() {
+        ed.registers.set_read_only(':', cmd.to_string());
+    }
+    if !ed.execute_ex_command(cmd) {
+        match cmd {
+            "w" => {
+                let _ = ed.save();
+            }
+            "q" | "quit" if ed.any_modified() => {
+                ed.set_status("No write since last change (add ! to override)")
+            }
+            "q" | "quit" | "q!" | "quit!" => return Ok(true),
+            "wq" | "x" => {
+                // Other buffers may still be hidden with changes
+                if ed.save().is_ok() {
+                    if !ed.any_modified() {
+                        return Ok(true);
+                    }
+                    ed.set_status("No write since last change (add ! to override)");
+                }
+            }
+            "wq!" | "x!" => {
+                if ed.save().is_ok() {
+                    return Ok(true);
+                }
+            }
+            _ => ed.set_status("Unknown command"),
+        }
+    }
+    ui.show_message_lines(ed, out)?;
+    Ok(false)
+}
+
+/// Opens whatever prompt a Normal/Visual key asked for. Returns `true` when
+/// the editor should quit.
+fn handle_input_result<W: Write>(
+    ed: &mut Editor,
+    ui: &mut Ui,
+    out: &mut W,
+    res: NormalInputResult,
+) -> io::Result<bool> {
+    let initial = match res {
+        NormalInputResult::CommandPrompt => "",
+        NormalInputResult::VisualCommandPrompt => "'<,'>",
+        NormalInputResult::SearchPrompt { backward } => {
+            ui.prompt_search(ed, &mut *out, backward)?;
+            return Ok(false);
+        }
+        NormalInputResult::None => return Ok(false),
+    };
+    if let Ok(Some(cmd)) = ui.prompt_command(ed, &mut *out, initial) {
+        return run_ex_command(ed, ui, out, &cmd);
+    }
+    Ok(false)
+}
+
 pub fn run() -> io::Result<()> {
     let mut ed = Editor::new()?;
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let p = PathBuf::from(&args[1]);
-        if let Err(e) = ed.open(p) {
+    // Each file gets a buffer; the first one is shown
+    let paths: Vec<PathBuf> = env::args().skip(1).map(PathBuf::from).collect();
+    for p in &paths {
+        if let Err(e) = ed.edit(p.clone()) {
             eprintln!("Failed to open file: {}", e);
         }
     }
+    if let Some(first) = paths.first() {
+        let _ = ed.edit(first.clone());
+    }
 
     let mut ui = Ui::new()?;
     enable_raw_mode()?;
@@ -37,7 +104,8 @@ pub fn run() -> io::Result<()> {
         let mut insert_undo_break_pending = false;
         let mut needs_redraw = true;
         loop {
-            if needs_redraw {
+            // A running macro is drawn once it is done
+            if needs_redraw && !ed.macro_running() {
                 ui.refresh(&mut stdout, &mut ed)?;
                 needs_redraw = false;
             }
@@ -51,13 +119,13 @@ pub fn run() -> io::Result<()> {
             if let Some(t) = ed.time_until_pending_timeout(SEQ_TIMEOUT_MS) {
                 timeout = std::cmp::min(timeout, t);
             }
-            if poll(timeout)? {
-                match read()? {
+            if let Some(event) = input::next_event(&mut ed, timeout)? {
+                match event {
                     Event::Key(KeyEvent {
                         code, modifiers, ..
                     }) => match (code, modifiers) {
                         (KeyCode::Char('q'), KeyModifiers::CONTROL) => {
-                            if ed.dirty && ed.quit_times > 0 {
+                            if ed.any_modified() && ed.quit_times > 0 {
                                 ed.set_status("File modified — press Ctrl-Q again to quit");
                                 ed.quit_times -= 1;
                                 needs_redraw = true;
@@ -84,18 +152,24 @@ pub fn run() -> io::Result<()> {
                                 needs_redraw = true;
                             }
                         }
+                        // `:s///c` waiting for y/n/a/q/l
+                        _ if ed.substitute_pending() => {
+                            match code {
+                                KeyCode::Char(c) => ed.substitute_answer(c),
+                                KeyCode::Esc => ed.substitute_answer('\x1b'),
+                                _ => {}
+                            }
+                            needs_redraw = true;
+                        }
                         _ => match ed.mode {
                             Mode::Insert => match code {
                                 KeyCode::Esc => {
-                                    ed.end_undo_group();
-                                    ed.mode = Mode::Normal;
+                                    ed.leave_insert();
                                     needs_redraw = true;
                                 }
                                 KeyCode::Char('z') if modifiers.contains(KeyModifiers::CONTROL) => {
                                     // Common muscle memory; Vim uses 'u' in normal, but support Ctrl-Z here in insert
-                                    if ed.undo() {
-                                        needs_redraw = true;
-                                    }
+                                    needs_redraw |= ed.undo();
                                 }
                                 KeyCode::Char('g') if modifiers.contains(KeyModifiers::CONTROL) => {
                                     // Start Ctrl-g sequence
@@ -107,65 +181,108 @@ pub fn run() -> io::Result<()> {
                                     insert_undo_break_pending = false;
                                     needs_redraw = true;
                                 }
+                                KeyCode::Enter
+                                | KeyCode::Backspace
+                                | KeyCode::Delete
+                                | KeyCode::Up
+                                | KeyCode::Down
+                                | KeyCode::Left
+                                | KeyCode::Right
+                                | KeyCode::Home
+                                | KeyCode::End
+                                | KeyCode::PageUp
+                                | KeyCode::PageDown => {
+                                    if let Some(c) = input::encode(&KeyEvent::new(code, modifiers))
+                                    {
+                                        ed.process_char(c);
+                                    }
+                                    needs_redraw = true;
+                                }
+                                KeyCode::Char(c) => {
+                                    insert_undo_break_pending = false;
+                                    if !modifiers.contains(KeyModifiers::CONTROL) && !c.is_control()
+                                    {
+                                        ed.process_char(c);
+                                        needs_redraw = true;
+                                    }
+                                }
+                                _ => {}
+                            },
+                            Mode::Replace => match code {
+                                KeyCode::Esc => {
+                                    ed.process_char('\x1b');
+                                    needs_redraw = true;
+                                }
                                 KeyCode::Enter => {
-                                    ed.insert_newline();
+                                    ed.process_char('\r');
                                     needs_redraw = true;
                                 }
-                                KeyCode::Backspace | KeyCode::Delete => {
-                                    ed.delete_char();
+                                KeyCode::Backspace => {
+                                    ed.process_char('\x7f');
                                     needs_redraw = true;
                                 }
-                                KeyCode::Up => {
-                                    ed.apply_action(Action::MoveUp);
+                                KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right => {
+                                    if let Some(c) = input::encode(&KeyEvent::new(code, modifiers))
+                                    {
+                                        ed.process_char(c);
+                                    }
                                     needs_redraw = true;
                                 }
-                                KeyCode::Down => {
-                                    ed.apply_action(Action::MoveDown);
+                                KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                                    ed.process_char(c);
                                     needs_redraw = true;
                                 }
-                                KeyCode::Left => {
-                                    ed.apply_action(Action::MoveLeft);
+                                _ => {}
+                            },
+                            Mode::Normal => match code {
+                                KeyCode::Esc => {
+                                    ed.process_normal_char('\x1b');
                                     needs_redraw = true;
                                 }
-                                KeyCode::Right => {
-                                    ed.apply_action(Action::MoveRight);
+                                KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => {
+                                    needs_redraw |= ed.redo();
+                                }
+                                KeyCode::Char(c)
+                                    if modifiers.contains(KeyModifiers::CONTROL)
+                                        && ed.window_pending() =>
+                                {
+                                    // `Ctrl-w Ctrl-j` is `Ctrl-w j`
+                                    ed.process_normal_char(c);
                                     needs_redraw = true;
                                 }
-                                KeyCode::Home => {
-                                    ed.apply_action(Action::LineStart);
+                                KeyCode::Char('w') if modifiers.contains(KeyModifiers::CONTROL) => {
+                                    ed.process_normal_char('\x17');
                                     needs_redraw = true;
                                 }
-                                KeyCode::End => {
-                                    ed.apply_action(Action::LineEnd);
+                                KeyCode::Char('v') if modifiers.contains(KeyModifiers::CONTROL) => {
+                                    ed.apply_action(Action::EnterVisualBlock);
                                     needs_redraw = true;
                                 }
-                                KeyCode::PageUp => {
-                                    ed.cy = ed.cy.saturating_sub(ui.screen_rows as usize);
+                                KeyCode::Char(c @ ('e' | 'y' | 'd' | 'u' | 'f' | 'b'))
+                                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                                {
+                                    // Scrolling, mapped as control codes so counts apply
+                                    ed.process_normal_char((c as u8 & 0x1f) as char);
                                     needs_redraw = true;
                                 }
-                                KeyCode::PageDown => {
-                                    ed.cy = (ed.cy + ui.screen_rows as usize)
-                                        .min(ed.buf.line_count().saturating_sub(1));
+                                KeyCode::Char('o') if modifiers.contains(KeyModifiers::CONTROL) => {
+                                    ed.process_normal_char('\x0f');
                                     needs_redraw = true;
                                 }
-                                KeyCode::Char(c) => {
-                                    insert_undo_break_pending = false;
-                                    if !modifiers.contains(KeyModifiers::CONTROL) && !c.is_control()
-                                    {
-                                        ed.insert_char(c);
-                                        needs_redraw = true;
-                                    }
+                                KeyCode::Char(c @ ('a' | 'x'))
+                                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                                {
+                                    ed.process_normal_char((c as u8 & 0x1f) as char);
+                                    needs_redraw = true;
                                 }
-                                _ => {}
-                            },
-                            Mode::Normal => match code {
-                                KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => {
-                                    if ed.redo() {
-                                        needs_redraw = true;
-                                    }
+                                KeyCode::Enter => {
+                                    // For `r<Enter>`
+                                    ed.process_normal_char('\r');
+                                    needs_redraw = true;
                                 }
-                                KeyCode::Char('v') if modifiers.contains(KeyModifiers::CONTROL) => {
-                                    ed.apply_action(Action::EnterVisualBlock);
+                                KeyCode::Tab => {
+                                    // Ctrl-i
+                                    ed.process_normal_char('\t');
                                     needs_redraw = true;
                                 }
                                 KeyCode::Up => {
@@ -193,52 +310,25 @@ pub fn run() -> io::Result<()> {
                                     needs_redraw = true;
                                 }
                                 KeyCode::PageUp => {
-                                    ed.cy = ed.cy.saturating_sub(ui.screen_rows as usize);
+                                    ed.apply_action(Action::ScrollPageUp);
                                     needs_redraw = true;
                                 }
                                 KeyCode::PageDown => {
-                                    ed.cy = (ed.cy + ui.screen_rows as usize)
-                                        .min(ed.buf.line_count().saturating_sub(1));
+                                    ed.apply_action(Action::ScrollPageDown);
                                     needs_redraw = true;
                                 }
                                 KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
-                                    match ed.process_normal_char(c) {
-                                        crate::editor::NormalInputResult::CommandPrompt => {
-                                            if let Ok(Some(cmd)) =
-                                                ui.prompt_command(&mut ed, &mut stdout)
-                                            {
-                                                if !ed.execute_ex_command(&cmd) {
-                                                    match cmd.as_str() {
-                                                        "w" => {
-                                                            let _ = ed.save();
-                                                            needs_redraw = true;
-                                                        }
-                                                        "q" => break,
-                                                        "wq" | "x" => {
-                                                            let _ = ed.save();
-                                                            break;
-                                                        }
-                                                        _ => {
-                                                            ed.set_status("Unknown command");
-                                                            needs_redraw = true;
-                                                        }
-                                                    }
-                                                } else {
-                                                    needs_redraw = true;
-                                                }
-                                            }
-                                        }
-                                        crate::editor::NormalInputResult::None => {
-                                            needs_redraw = true;
-                                        }
+                                    let res = ed.process_normal_char(c);
+                                    if handle_input_result(&mut ed, &mut ui, &mut stdout, res)? {
+                                        break;
                                     }
+                                    needs_redraw = true;
                                 }
                                 _ => {}
                             },
                             Mode::Visual | Mode::VisualLine | Mode::VisualBlock => match code {
                                 KeyCode::Esc => {
-                                    ed.mode = Mode::Normal;
-                                    ed.visual_anchor = None;
+                                    ed.exit_visual();
                                     needs_redraw = true;
                                 }
                                 KeyCode::Up => {
@@ -265,28 +355,24 @@ pub fn run() -> io::Result<()> {
                                     ed.apply_action(Action::LineEnd);
                                     needs_redraw = true;
                                 }
+                                KeyCode::PageUp => {
+                                    ed.apply_action(Action::ScrollPageUp);
+                                    needs_redraw = true;
+                                }
+                                KeyCode::PageDown => {
+                                    ed.apply_action(Action::ScrollPageDown);
+                                    needs_redraw = true;
+                                }
+                                KeyCode::Char(c @ ('e' | 'y' | 'd' | 'u' | 'f' | 'b'))
+                                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                                {
+                                    ed.process_visual_char((c as u8 & 0x1f) as char);
+                                    needs_redraw = true;
+                                }
                                 KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => {
-                                    match c {
-                                        'h' => ed.apply_action(Action::MoveLeft),
-                                        'j' => ed.apply_action(Action::MoveDown),
-                                        'k' => ed.apply_action(Action::MoveUp),
-                                        'l' => ed.apply_action(Action::MoveRight),
-                                        '0' => ed.apply_action(Action::LineStart),
-                                        '$' => ed.apply_action(Action::LineEnd),
-                                        'w' => ed.apply_action(Action::MoveWordForward),
-                                        'b' => ed.apply_action(Action::MoveWordBackward),
-                                        'e' => ed.apply_action(Action::MoveEndWord),
-                                        'v' => ed.apply_action(Action::EnterVisual),
-                                        'd' => {
-                                            ed.visual_delete();
-                                        }
-                                        'y' => {
-                                            ed.visual_yank();
-                                        }
-                                        'c' => {
-                                            ed.visual_change();
-                                        }
-                                        _ => {}
+                                    let res = ed.process_visual_char(c);
+                                    if handle_input_result(&mut ed, &mut ui, &mut stdout, res)? {
+                                        break;
                                     }
                                     needs_redraw = true;
                                 }
@@ -317,34 +403,11 @@ pub fn run() -> io::Result<()> {
                     .map(|d| d.is_zero())
                     .unwrap_or(false)
                 {
-                    match ed.process_pending_timeout() {
-                        crate::editor::NormalInputResult::CommandPrompt => {
-                            if let Ok(Some(cmd)) = ui.prompt_command(&mut ed, &mut stdout) {
-                                if !ed.execute_ex_command(&cmd) {
-                                    match cmd.as_str() {
-                                        "w" => {
-                                            let _ = ed.save();
-                                            needs_redraw = true;
-                                        }
-                                        "q" => break,
-                                        "wq" | "x" => {
-                                            let _ = ed.save();
-                                            break;
-                                        }
-                                        _ => {
-                                            ed.set_status("Unknown command");
-                                            needs_redraw = true;
-                                        }
-                                    }
-                                } else {
-                                    needs_redraw = true;
-                                }
-                            }
-                        }
-                        crate::editor::NormalInputResult::None => {
-                            needs_redraw = true;
-                        }
+                    let res = ed.process_pending_timeout();
+                    if handle_input_result(&mut ed, &mut ui, &mut stdout, res)? {
+                        break;
                     }
+                    needs_redraw = true;
                 }
             }
         }
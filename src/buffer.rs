@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+
 use ropey::Rope;
+
+use crate::regex::{Captures, Regex};
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
@@ -8,37 +12,253 @@ pub enum BufferError {
     InvalidColumn { column: usize, max_columns: usize },
 }
 
+/// Line-ending convention of a file on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Every line break is `\n`.
+    Lf,
+    /// Every line break is `\r\n`; the `\r` is dropped in the buffer and restored on save.
+    Crlf,
+    /// Both styles occur; carriage returns stay in the buffer as ordinary content.
+    Mixed,
+}
+
+impl LineEnding {
+    pub fn detect(s: &str) -> Self {
+        let lf = s.matches('\n').count();
+        let crlf = s.matches("\r\n").count();
+        if crlf == 0 {
+            LineEnding::Lf
+        } else if crlf == lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Mixed
+        }
+    }
+
+    /// Name used by `:set fileformat` and the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "unix",
+            LineEnding::Crlf => "dos",
+            LineEnding::Mixed => "mixed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Lf),
+            "dos" => Some(LineEnding::Crlf),
+            _ => None,
+        }
+    }
+}
+
+/// A single rope edit in char offsets, recorded so it can be reverted or replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditOp {
+    Insert { at: usize, text: String },
+    Remove { at: usize, text: String },
+}
+
+impl EditOp {
+    pub fn inverse(&self) -> EditOp {
+        match self {
+            EditOp::Insert { at, text } => EditOp::Remove {
+                at: *at,
+                text: text.clone(),
+            },
+            EditOp::Remove { at, text } => EditOp::Insert {
+                at: *at,
+                text: text.clone(),
+            },
+        }
+    }
+}
+
+/// Ascending char positions that every edit shifts from some index on.
+/// Shifting them one by one would make `:g` over many lines quadratic, so
+/// they are kept as a Fenwick tree of the differences between neighbours:
+/// a shift and a lookup both take `O(log n)`.
+#[derive(Clone, Default)]
+struct Positions {
+    tree: Vec<isize>,
+}
+
+impl Positions {
+    fn new(sorted: &[usize]) -> Self {
+        let mut tree: Vec<isize> = sorted
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| p as isize - i.checked_sub(1).map_or(0, |j| sorted[j] as isize))
+            .collect();
+        for i in 1..=tree.len() {
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= tree.len() {
+                tree[parent - 1] += tree[i - 1];
+            }
+        }
+        Self { tree }
+    }
+
+    fn get(&self, k: usize) -> usize {
+        let mut sum = 0;
+        let mut i = k + 1;
+        while i > 0 {
+            sum += self.tree[i - 1];
+            i &= i - 1;
+        }
+        sum as usize
+    }
+
+    /// Adds `delta` to positions `k..`.
+    fn shift_from(&mut self, k: usize, delta: isize) {
+        let mut i = k + 1;
+        while i <= self.tree.len() {
+            self.tree[i - 1] += delta;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    fn set(&mut self, k: usize, p: usize) {
+        let delta = p as isize - self.get(k) as isize;
+        self.shift_from(k, delta);
+        self.shift_from(k + 1, -delta);
+    }
+
+    /// Number of positions before `p`.
+    fn count_below(&self, p: usize) -> usize {
+        let (mut k, mut rest) = (0, p as isize);
+        let mut step = self.tree.len().checked_ilog2().map_or(0, |b| 1 << b);
+        while step > 0 {
+            if k + step <= self.tree.len() && self.tree[k + step - 1] < rest {
+                k += step;
+                rest -= self.tree[k - 1];
+            }
+            step >>= 1;
+        }
+        k
+    }
+}
+
 #[derive(Clone)]
 pub struct Buffer {
     rope: Rope,
+    /// Edits made since the last `take_changes`.
+    journal: Vec<EditOp>,
+    /// Char positions of tracked lines (see `track_lines`), kept in step with
+    /// every edit. They stay sorted, so an edit only touches those after it.
+    tracked: Positions,
+    /// Tracked lines that have been deleted.
+    tracked_gone: Vec<bool>,
+    /// Marks (see `set_mark`) as the char position of their line's start
+    /// and a display column. Like tracked lines they stay on their line
+    /// through edits, but are dropped outright when it is deleted.
+    marks: HashMap<char, (usize, usize)>,
+    /// The jump list, oldest first, kept in step in the same way.
+    jumps: Vec<(usize, usize)>,
+    /// Cursor (line start and display column) and top line start of the
+    /// windows showing this buffer other than the current one, by window.
+    /// They follow edits like marks, but move to where their line was
+    /// instead of going away with it.
+    views: HashMap<usize, ((usize, usize), usize)>,
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Self {
             rope: Rope::from_str(""),
+            journal: Vec::new(),
+            tracked: Positions::default(),
+            tracked_gone: Vec::new(),
+            marks: HashMap::new(),
+            jumps: Vec::new(),
+            views: HashMap::new(),
         }
     }
 }
 
 impl Buffer {
     pub const TABSTOP: usize = 4;
+    pub const JUMPLIST_MAX: usize = 100;
 
-    fn gw_at(col: usize, g: &str) -> usize {
+    /// Display width of grapheme `g` when drawn starting at column `col`.
+    pub fn gw_at(col: usize, g: &str) -> usize {
         if g == "\t" {
             let ts = Self::TABSTOP.max(1);
             let next_tab = ((col / ts) + 1) * ts;
             next_tab - col
+        } else if Self::control_char(g).is_some() {
+            // Shown in caret notation, e.g. `^M`
+            2
+        } else if Self::raw_byte(g).is_some() {
+            // Shown as hex, e.g. `<ff>`
+            4
         } else {
             UnicodeWidthStr::width(g).max(1)
         }
     }
+    /// Returns the character if `g` is a lone control character (other than tab).
+    pub fn control_char(g: &str) -> Option<char> {
+        let mut chars = g.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_control() && c != '\t' => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Returns the byte if `g` is an undecodable byte kept from the file.
+    pub fn raw_byte(g: &str) -> Option<u8> {
+        g.chars().next().and_then(crate::encoding::escaped_byte)
+    }
+
     pub fn from_string(s: String) -> Self {
         Self {
-            rope: Rope::from_str(&s.replace('\r', "")),
+            rope: Rope::from_str(&s),
+            journal: Vec::new(),
+            tracked: Positions::default(),
+            tracked_gone: Vec::new(),
+            marks: HashMap::new(),
+            jumps: Vec::new(),
+            views: HashMap::new(),
+        }
+    }
+
+    /// Builds a buffer from raw file text, detecting and normalizing its line endings.
+    /// Only `\r\n` pairs of a pure CRLF file are folded to `\n`; any other `\r` is kept.
+    pub fn from_file_text(s: &str) -> (Self, LineEnding) {
+        let ending = LineEnding::detect(s);
+        let buf = if ending == LineEnding::Crlf {
+            Self::from_string(s.replace("\r\n", "\n"))
+        } else {
+            Self::from_string(s.to_string())
+        };
+        (buf, ending)
+    }
+
+    /// Renders the buffer for writing with the given line-ending convention.
+    pub fn to_file_text(&self, ending: LineEnding) -> String {
+        let text = self.to_string();
+        match ending {
+            LineEnding::Crlf => text.replace('\n', "\r\n"),
+            LineEnding::Lf | LineEnding::Mixed => text,
         }
     }
 
+    /// Removes a trailing `\r` from every line. Returns the number of lines changed.
+    pub fn strip_trailing_cr(&mut self) -> usize {
+        let mut changed = 0;
+        for y in 0..self.line_count() {
+            let line = self.line_string(y);
+            if line.ends_with('\r') {
+                let idx = self.line_start_char(y) + line.chars().count() - 1;
+                self.rope_remove(idx..idx + 1);
+                changed += 1;
+            }
+        }
+        changed
+    }
+
     #[cfg(test)]
     pub fn from_lines(lines: Vec<String>) -> Self {
         Self::from_string(lines.join("\n"))
@@ -52,6 +272,265 @@ impl Buffer {
             .collect()
     }
 
+    /// All mutations go through `rope_insert`/`rope_remove` so they are journaled
+    /// and leave the `'.` mark where they happened.
+    /// Consecutive typing and backspacing are merged into one op.
+    fn rope_insert(&mut self, idx: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        self.shift_tracked_insert(idx, s.chars().count());
+        self.rope.insert(idx, s);
+        self.mark_change(idx);
+        if let Some(EditOp::Insert { at, text }) = self.journal.last_mut() {
+            if *at + text.chars().count() == idx {
+                text.push_str(s);
+                return;
+            }
+        }
+        self.journal.push(EditOp::Insert {
+            at: idx,
+            text: s.to_string(),
+        });
+    }
+
+    fn rope_remove(&mut self, range: std::ops::Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let removed = self.rope.slice(range.clone()).to_string();
+        self.shift_tracked_remove(range.clone());
+        self.rope.remove(range.clone());
+        self.mark_change(range.start);
+        if let Some(EditOp::Remove { at, text }) = self.journal.last_mut() {
+            if range.end == *at {
+                text.insert_str(0, &removed);
+                *at = range.start;
+                return;
+            }
+        }
+        self.journal.push(EditOp::Remove {
+            at: range.start,
+            text: removed,
+        });
+    }
+
+    fn mark_change(&mut self, idx: usize) {
+        let (y, col) = self.char_to_pos(idx);
+        self.set_mark('.', y, col);
+    }
+
+    /// Drains the edits recorded since the previous call.
+    pub fn take_changes(&mut self) -> Vec<EditOp> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// Applies `op` without journaling it (used by undo/redo).
+    /// Offsets are clamped so a stale op can never panic.
+    pub fn apply(&mut self, op: &EditOp) {
+        let len = self.rope.len_chars();
+        match op {
+            EditOp::Insert { at, text } => {
+                self.shift_tracked_insert((*at).min(len), text.chars().count());
+                self.rope.insert((*at).min(len), text);
+            }
+            EditOp::Remove { at, text } => {
+                let start = (*at).min(len);
+                let end = (start + text.chars().count()).min(len);
+                self.shift_tracked_remove(start..end);
+                self.rope.remove(start..end);
+            }
+        }
+    }
+
+    /// Starts following `lines` (ascending) through later edits, replacing
+    /// any lines tracked before. `tracked_line(i)` then finds where the
+    /// `i`th one has moved to.
+    pub fn track_lines(&mut self, lines: &[usize]) {
+        let last = self.line_count() - 1;
+        let starts: Vec<usize> = lines
+            .iter()
+            .map(|&y| self.line_start_char(y.min(last)))
+            .collect();
+        self.tracked = Positions::new(&starts);
+        self.tracked_gone = vec![false; lines.len()];
+    }
+
+    /// Current line of the `i`th tracked line, or `None` if it was deleted.
+    pub fn tracked_line(&self, i: usize) -> Option<usize> {
+        if *self.tracked_gone.get(i)? {
+            return None;
+        }
+        Some(self.rope.char_to_line(self.tracked.get(i)))
+    }
+
+    pub fn untrack_lines(&mut self) {
+        self.tracked = Positions::default();
+        self.tracked_gone.clear();
+    }
+
+    fn shift_tracked_insert(&mut self, idx: usize, n: usize) {
+        let k = self.tracked.count_below(idx);
+        self.tracked.shift_from(k, n as isize);
+        for (p, _) in self.marks.values_mut().chain(self.jumps.iter_mut()) {
+            if *p >= idx {
+                *p += n;
+            }
+        }
+        for ((p, _), top) in self.views.values_mut() {
+            for p in [p, top] {
+                if *p >= idx {
+                    *p += n;
+                }
+            }
+        }
+    }
+
+    /// A tracked line is dropped when the removal takes all of it, including
+    /// its line break (or the one before it, for the last line).
+    fn shift_tracked_remove(&mut self, range: std::ops::Range<usize>) {
+        let (s, e) = (range.start, range.end);
+        let k0 = self.tracked.count_below(s);
+        let k1 = self.tracked.count_below(e);
+        for i in k0..k1 {
+            if self.removes_line_at(self.tracked.get(i), &range) {
+                self.tracked_gone[i] = true;
+            }
+            self.tracked.set(i, s);
+        }
+        self.tracked.shift_from(k1, -((e - s) as isize));
+        let mut marks = std::mem::take(&mut self.marks);
+        marks.retain(|_, (p, _)| self.shift_removed(p, &range));
+        self.marks = marks;
+        let mut jumps = std::mem::take(&mut self.jumps);
+        jumps.retain_mut(|(p, _)| self.shift_removed(p, &range));
+        self.jumps = jumps;
+        let mut views = std::mem::take(&mut self.views);
+        for ((p, _), top) in views.values_mut() {
+            for p in [p, top] {
+                if !self.shift_removed(p, &range) {
+                    *p = range.start;
+                }
+            }
+        }
+        self.views = views;
+    }
+
+    /// Moves line position `p` for the removal of `range`; false if the
+    /// removal takes its whole line.
+    fn shift_removed(&self, p: &mut usize, range: &std::ops::Range<usize>) -> bool {
+        if *p >= range.end {
+            *p -= range.len();
+        } else if *p >= range.start {
+            if self.removes_line_at(*p, range) {
+                return false;
+            }
+            *p = range.start;
+        }
+        true
+    }
+
+    /// Whether removing `range` takes all of the line holding char `p`,
+    /// including its line break (or the one before it, for the last line).
+    fn removes_line_at(&self, p: usize, range: &std::ops::Range<usize>) -> bool {
+        let y = self.rope.char_to_line(p);
+        let start = self.rope.line_to_char(y);
+        let line = self.rope.line(y);
+        let mut end = start + line.len_chars();
+        if line.chars().last() == Some('\n') {
+            end -= 1;
+        }
+        let (s, e) = (range.start, range.end);
+        s <= start && e >= end && (e > end || s < start)
+    }
+
+    /// Sets mark `name` at display column `col` of line `y`. It then
+    /// follows the line as lines are inserted or deleted above it.
+    pub fn set_mark(&mut self, name: char, y: usize, col: usize) {
+        let y = y.min(self.line_count().saturating_sub(1));
+        self.marks.insert(name, (self.line_start_char(y), col));
+    }
+
+    /// Line and display column of mark `name`, or `None` if it is not set
+    /// or its line has been deleted.
+    pub fn mark(&self, name: char) -> Option<(usize, usize)> {
+        self.marks
+            .get(&name)
+            .map(|&(p, col)| (self.rope.char_to_line(p), col))
+    }
+
+    pub fn remove_mark(&mut self, name: char) {
+        self.marks.remove(&name);
+    }
+
+    /// Names of the marks that are set, in no particular order.
+    pub fn mark_names(&self) -> Vec<char> {
+        self.marks.keys().copied().collect()
+    }
+
+    /// Keeps the cursor `(y, col)` and top line of window `id`, which shows
+    /// this buffer but isn't the current window.
+    pub fn set_view(&mut self, id: usize, (y, col): (usize, usize), top: usize) {
+        let last = self.line_count().saturating_sub(1);
+        let cursor = (self.line_start_char(y.min(last)), col);
+        let top = self.line_start_char(top.min(last));
+        self.views.insert(id, (cursor, top));
+    }
+
+    /// Cursor and top line of window `id`, as kept by `set_view` and moved
+    /// along by edits since.
+    pub fn view(&self, id: usize) -> Option<((usize, usize), usize)> {
+        self.views.get(&id).map(|&((p, col), top)| {
+            let line = |p: usize| self.rope.char_to_line(p.min(self.rope.len_chars()));
+            ((line(p), col), line(top))
+        })
+    }
+
+    pub fn remove_view(&mut self, id: usize) -> Option<((usize, usize), usize)> {
+        let view = self.view(id);
+        self.views.remove(&id);
+        view
+    }
+
+    /// Windows with a view kept in the buffer.
+    pub fn view_ids(&self) -> Vec<usize> {
+        self.views.keys().copied().collect()
+    }
+
+    /// Adds line `y` to the end of the jump list, dropping an older entry
+    /// for the same line and the oldest one past `JUMPLIST_MAX`.
+    pub fn push_jump(&mut self, y: usize, col: usize) {
+        let at = self.line_start_char(y.min(self.line_count().saturating_sub(1)));
+        self.jumps.retain(|&(p, _)| p != at);
+        self.jumps.push((at, col));
+        if self.jumps.len() > Self::JUMPLIST_MAX {
+            self.jumps.remove(0);
+        }
+    }
+
+    /// Line and display column of the `i`th jump, oldest first.
+    pub fn jump(&self, i: usize) -> Option<(usize, usize)> {
+        self.jumps
+            .get(i)
+            .map(|&(p, col)| (self.rope.char_to_line(p), col))
+    }
+
+    pub fn jump_count(&self) -> usize {
+        self.jumps.len()
+    }
+
+    /// FNV-1a hash of the text, computed chunk by chunk without copying it.
+    pub fn content_hash(&self) -> u64 {
+        let mut h: u64 = 0xcbf29ce484222325;
+        for chunk in self.rope.chunks() {
+            for &b in chunk.as_bytes() {
+                h ^= b as u64;
+                h = h.wrapping_mul(0x100000001b3);
+            }
+        }
+        h
+    }
+
     pub fn line_count(&self) -> usize {
         self.rope.len_lines()
     }
@@ -86,10 +565,15 @@ impl Buffer {
         acc
     }
 
-    fn line_start_char(&self, y: usize) -> usize {
+    pub fn line_start_char(&self, y: usize) -> usize {
         self.rope.line_to_char(y)
     }
 
+    /// Char at index `idx`, or `None` past the end.
+    pub fn char_at(&self, idx: usize) -> Option<char> {
+        (idx < self.rope.len_chars()).then(|| self.rope.char(idx))
+    }
+
     fn col_to_line_byte(&self, y: usize, col: usize) -> usize {
         let row = self.line_string(y);
         let mut acc = 0usize;
@@ -136,13 +620,13 @@ impl Buffer {
         let idx = self.col_to_char_index(y, col);
         let mut buf = [0u8; 4];
         let s = ch.encode_utf8(&mut buf);
-        self.rope.insert(idx, s);
+        self.rope_insert(idx, s);
         Ok(())
     }
 
     pub fn insert_newline(&mut self, col: usize, y: usize) {
         let idx = self.col_to_char_index(y, col);
-        self.rope.insert(idx, "\n");
+        self.rope_insert(idx, "\n");
     }
 
     pub fn delete_line(&mut self, y: usize) {
@@ -155,7 +639,24 @@ impl Buffer {
         } else {
             self.rope.len_chars()
         };
-        self.rope.remove(start..end);
+        self.rope_remove(start..end);
+    }
+
+    /// Removes lines `sy..=ey` entirely. Removing the last lines also takes
+    /// the line break before them, so no empty line is left behind.
+    pub fn remove_lines(&mut self, sy: usize, ey: usize) {
+        let count = self.line_count();
+        if sy >= count {
+            return;
+        }
+        let mut start = self.line_start_char(sy);
+        let end = if ey + 1 < count {
+            self.line_start_char(ey + 1)
+        } else {
+            start = start.saturating_sub(1);
+            self.rope.len_chars()
+        };
+        self.rope_remove(start..end);
     }
 
     /// Deletes the character before the specified column position.
@@ -209,9 +710,10 @@ impl Buffer {
         let end_chars = row[..end_b].chars().count();
         let start = self.line_start_char(y) + start_chars;
         let end = self.line_start_char(y) + end_chars;
-        self.rope.remove(start..end);
+        self.rope_remove(start..end);
     }
 
+    #[cfg(test)]
     pub fn delete_at(&mut self, col: usize, y: usize) {
         let row = self.line_string(y);
         if row.is_empty() {
@@ -237,7 +739,7 @@ impl Buffer {
             let end_chars = row[..e].chars().count();
             let start = self.line_start_char(y) + start_chars;
             let end = self.line_start_char(y) + end_chars;
-            self.rope.remove(start..end);
+            self.rope_remove(start..end);
         }
     }
 
@@ -249,7 +751,7 @@ impl Buffer {
         let start = self.line_start_char(y);
         if start > 0 {
             // remove the newline just before this line
-            self.rope.remove((start - 1)..start);
+            self.rope_remove((start - 1)..start);
         }
         new_x
     }
@@ -354,6 +856,44 @@ impl Buffer {
         self.byte_to_col_in_line(y, target_b)
     }
 
+    /// Column of the `count`th grapheme starting with `ch` after (or before)
+    /// the one at `col` on line `y`, skipping `skip` graphemes next to it
+    /// first. `None` if the line has fewer matches.
+    pub fn find_in_line(
+        &self,
+        y: usize,
+        col: usize,
+        ch: char,
+        forward: bool,
+        count: usize,
+        skip: usize,
+    ) -> Option<usize> {
+        let row = self.line_string(y);
+        let mut cols = Vec::new();
+        let mut at = 0usize;
+        let mut acc = 0usize;
+        for g in row.graphemes(true) {
+            if acc <= col {
+                at = cols.len();
+            }
+            cols.push((acc, g.starts_with(ch)));
+            acc += Self::gw_at(acc, g);
+        }
+        let found = if forward {
+            cols.get(at + 1 + skip..)?
+                .iter()
+                .filter(|(_, m)| *m)
+                .nth(count.max(1) - 1)
+        } else {
+            cols.get(..at.checked_sub(skip)?)?
+                .iter()
+                .rev()
+                .filter(|(_, m)| *m)
+                .nth(count.max(1) - 1)
+        };
+        found.map(|&(c, _)| c)
+    }
+
     // Utilities for editor multi-line ops
     pub fn char_index_at_col(&self, y: usize, col: usize) -> usize {
         self.col_to_char_index(y, col)
@@ -361,7 +901,15 @@ impl Buffer {
 
     pub fn remove_char_range(&mut self, start_char: usize, end_char: usize) {
         if start_char < end_char && end_char <= self.rope.len_chars() {
-            self.rope.remove(start_char..end_char);
+            self.rope_remove(start_char..end_char);
+        }
+    }
+
+    /// Replaces chars `start_char..end_char` with `s`.
+    pub fn replace_char_range(&mut self, start_char: usize, end_char: usize, s: &str) {
+        self.remove_char_range(start_char, end_char);
+        if !s.is_empty() {
+            self.rope_insert(start_char, s);
         }
     }
 
@@ -376,7 +924,7 @@ impl Buffer {
         let start = self.line_start_char(y);
         let chars_in_line = self.line_string(y).chars().count();
         if chars_in_line > 0 {
-            self.rope.remove(start..(start + chars_in_line));
+            self.rope_remove(start..(start + chars_in_line));
         }
     }
 
@@ -386,7 +934,11 @@ impl Buffer {
         } else {
             self.col_to_char_index(y, col)
         };
-        self.rope.insert(idx, s);
+        self.rope_insert(idx, s);
+    }
+
+    pub fn insert_at_char(&mut self, idx: usize, s: &str) {
+        self.rope_insert(idx.min(self.rope.len_chars()), s);
     }
 
     pub fn insert_str_at_line_start(&mut self, y: usize, s: &str) {
@@ -395,7 +947,91 @@ impl Buffer {
         } else {
             self.line_start_char(y)
         };
-        self.rope.insert(idx, s);
+        self.rope_insert(idx, s);
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    /// Line and display column of char index `idx`.
+    pub fn char_to_pos(&self, idx: usize) -> (usize, usize) {
+        let idx = idx.min(self.rope.len_chars());
+        let y = self.rope.char_to_line(idx);
+        let byte = self.rope.char_to_byte(idx) - self.rope.line_to_byte(y);
+        (y, self.byte_to_col_in_line(y, byte))
+    }
+
+    /// Leftmost match of `re` starting at a char index in `from..=until`.
+    /// The rope is streamed from `from`; nothing is copied out of it.
+    pub fn find_regex(&self, re: &Regex, from: usize, until: usize) -> Option<Captures> {
+        let len = self.rope.len_chars();
+        if from > len {
+            return None;
+        }
+        let prev = from.checked_sub(1).map(|i| self.rope.char(i));
+        re.search(self.rope.chars_at(from), from, prev, until.min(len))
+    }
+
+    /// Last match of `re` starting strictly before char index `before`.
+    pub fn rfind_regex(&self, re: &Regex, before: usize) -> Option<Captures> {
+        let last = before.checked_sub(1)?.min(self.rope.len_chars());
+        let mut y = self.rope.char_to_line(last);
+        loop {
+            let mut from = self.line_start_char(y);
+            let until = last.min(from + self.line_string(y).chars().count());
+            let mut found = None;
+            while let Some(m) = self.find_regex(re, from, until) {
+                from = m.start() + 1;
+                found = Some(m);
+                if from > until {
+                    break;
+                }
+            }
+            // Lines are scanned bottom-up so a hit stops the scan early
+            if found.is_some() || y == 0 {
+                return found;
+            }
+            y -= 1;
+        }
+    }
+
+    /// Whether a match of `re` starts on line `y`.
+    pub fn line_has_match(&self, re: &Regex, y: usize) -> bool {
+        if y >= self.line_count() {
+            return false;
+        }
+        let start = self.line_start_char(y);
+        let end = start + self.line_string(y).chars().count();
+        self.find_regex(re, start, end).is_some()
+    }
+
+    /// Display-column ranges `[start, end)` of matches starting on line `y`.
+    /// A match running past the line end is cut at the end of the line.
+    pub fn regex_line_matches(&self, re: &Regex, y: usize) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        if y >= self.line_count() {
+            return out;
+        }
+        let start = self.line_start_char(y);
+        let line_end = start + self.line_string(y).chars().count();
+        let width = self.line_width(y);
+        let mut from = start;
+        while let Some(m) = self.find_regex(re, from, line_end) {
+            let (_, sc) = self.char_to_pos(m.start());
+            let ec = if m.end() > line_end {
+                width
+            } else {
+                self.char_to_pos(m.end()).1
+            };
+            out.push((sc, ec));
+            // Empty matches advance by one char; others resume after the match
+            from = m.end().max(m.start() + 1);
+            if from > line_end {
+                break;
+            }
+        }
+        out
     }
 
     pub fn byte_to_col_in_line(&self, y: usize, target_b: usize) -> usize {
@@ -510,4 +1146,186 @@ mod tests {
         assert_eq!(b.prev_col(4, 0), 1);
         assert_eq!(b.prev_col(1, 0), 0);
     }
+
+    #[test]
+    fn journal_records_and_reverts_edits() {
+        let mut b = Buffer::from_lines(vec!["abc".to_string(), "def".to_string()]);
+        b.insert_char(3, 0, 'x').unwrap();
+        b.insert_char(4, 0, 'y').unwrap();
+        b.delete_line(1);
+        let ops = b.take_changes();
+        // Typing "xy" coalesces into a single insert
+        assert_eq!(ops.len(), 2);
+        assert_eq!(
+            ops[0],
+            EditOp::Insert {
+                at: 3,
+                text: "xy".to_string()
+            }
+        );
+        for op in ops.iter().rev() {
+            b.apply(&op.inverse());
+        }
+        assert_eq!(b.to_string(), "abc\ndef");
+        assert!(b.take_changes().is_empty());
+        for op in &ops {
+            b.apply(op);
+        }
+        assert_eq!(b.to_string(), "abcxy\n");
+    }
+
+    #[test]
+    fn backspaces_coalesce_into_one_remove() {
+        let mut b = Buffer::from_lines(vec!["hello".to_string()]);
+        let c = b.delete_prev(5, 0);
+        b.delete_prev(c, 0);
+        assert_eq!(
+            b.take_changes(),
+            vec![EditOp::Remove {
+                at: 3,
+                text: "lo".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn line_ending_detection() {
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\r\nb\n"), LineEnding::Mixed);
+        assert_eq!(LineEnding::detect("no newline"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn crlf_round_trip_keeps_lone_carriage_returns() {
+        let text = "one\r\ntw\ro\r\nthree\r\r\n";
+        let (b, ending) = Buffer::from_file_text(text);
+        assert_eq!(ending, LineEnding::Crlf);
+        assert_eq!(b.line_count(), 4);
+        assert_eq!(b.line_string(1), "tw\ro");
+        assert_eq!(b.line_string(2), "three\r");
+        assert_eq!(b.to_file_text(ending), text);
+    }
+
+    #[test]
+    fn mixed_endings_are_preserved_verbatim() {
+        let text = "a\r\nb\nc\r\n";
+        let (mut b, ending) = Buffer::from_file_text(text);
+        assert_eq!(ending, LineEnding::Mixed);
+        assert_eq!(b.line_count(), 4);
+        assert_eq!(b.line_string(0), "a\r");
+        assert_eq!(b.to_file_text(ending), text);
+        assert_eq!(b.strip_trailing_cr(), 2);
+        assert_eq!(b.to_file_text(LineEnding::Crlf), "a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn control_chars_use_caret_width() {
+        let b = Buffer::from_string("a\rb".to_string());
+        assert_eq!(b.line_count(), 1);
+        assert_eq!(b.line_width(0), 4);
+        assert_eq!(b.next_col(1, 0), 3);
+    }
+
+    #[test]
+    fn regex_search_runs_over_the_rope() {
+        let mut text = String::new();
+        for i in 0..2000 {
+            text.push_str(&format!("line {}\n", i));
+        }
+        text.push_str("\tend\nof file");
+        let b = Buffer::from_string(text);
+        let re = Regex::new("end\\nof", false).unwrap();
+        let m = b.find_regex(&re, 0, b.len_chars()).unwrap();
+        assert_eq!(b.char_to_pos(m.start()), (2000, 4));
+        assert_eq!(b.char_to_pos(m.end()), (2001, 2));
+
+        let re = Regex::new("^line 1\\d*$", false).unwrap();
+        let last = b.rfind_regex(&re, b.len_chars()).unwrap();
+        assert_eq!(b.char_to_pos(last.start()), (1999, 0));
+        let before = b.rfind_regex(&re, last.start()).unwrap();
+        assert_eq!(b.char_to_pos(before.start()), (1998, 0));
+        assert_eq!(b.regex_line_matches(&re, 1999), vec![(0, 9)]);
+        assert!(b.regex_line_matches(&re, 2).is_empty());
+    }
+
+    #[test]
+    fn positions_shift_like_a_plain_list() {
+        let mut plain = vec![0, 3, 3, 7, 12, 20, 21];
+        let mut pos = Positions::new(&plain);
+        let check = |pos: &Positions, plain: &[usize]| {
+            let got: Vec<usize> = (0..plain.len()).map(|k| pos.get(k)).collect();
+            assert_eq!(got, plain);
+            for p in 0..25 {
+                assert_eq!(pos.count_below(p), plain.partition_point(|&q| q < p));
+            }
+        };
+        check(&pos, &plain);
+        pos.shift_from(3, 5);
+        plain[3..].iter_mut().for_each(|p| *p += 5);
+        check(&pos, &plain);
+        pos.set(4, 13);
+        plain[4] = 13;
+        check(&pos, &plain);
+        pos.shift_from(5, -10);
+        plain[5..].iter_mut().for_each(|p| *p -= 10);
+        check(&pos, &plain);
+    }
+
+    #[test]
+    fn tracked_lines_follow_edits() {
+        let mut b = Buffer::from_lines(vec!["a".into(), "b".into(), "c".into(), "d".into()]);
+        b.track_lines(&[1, 2, 3]);
+        b.insert_str_at_line_start(0, "new\n");
+        assert_eq!(b.tracked_line(0), Some(2));
+        // Clearing a line keeps it; deleting it drops it
+        b.clear_line(2);
+        assert_eq!(b.tracked_line(0), Some(2));
+        b.remove_lines(2, 2);
+        assert_eq!(b.tracked_line(0), None);
+        assert_eq!(b.tracked_line(1), Some(2));
+        b.remove_lines(3, 3);
+        assert_eq!(b.tracked_line(2), None);
+        b.apply(&EditOp::Insert {
+            at: 0,
+            text: "x\ny\n".into(),
+        });
+        assert_eq!(b.tracked_line(1), Some(4));
+        b.untrack_lines();
+        assert_eq!(b.tracked_line(1), None);
+    }
+
+    #[test]
+    fn marks_and_jumps_stay_on_their_line() {
+        let mut b = Buffer::from_lines(vec!["a".into(), "bb".into(), "c".into()]);
+        b.set_mark('a', 1, 1);
+        b.push_jump(2, 0);
+        b.set_view(7, (1, 1), 0);
+        b.insert_str_at_line_start(0, "new\n");
+        assert_eq!(b.mark('a'), Some((2, 1)));
+        assert_eq!(b.view(7), Some(((2, 1), 1)));
+        assert_eq!(b.mark('.'), Some((0, 0)));
+        b.clear_line(2);
+        b.remove_lines(0, 0);
+        assert_eq!(b.mark('a'), Some((1, 1)));
+        assert_eq!(b.jump(0), Some((2, 0)));
+        // Deleting the line drops what was on it
+        b.remove_lines(1, 1);
+        assert_eq!(b.mark('a'), None);
+        assert_eq!(b.jump(0), Some((1, 0)));
+        // A window's cursor moves off the deleted line instead
+        assert_eq!(b.view(7), Some(((1, 1), 0)));
+        assert_eq!(b.mark('.'), Some((1, 0)));
+        // Undo and redo move them too
+        b.apply(&EditOp::Insert {
+            at: 0,
+            text: "x\n".into(),
+        });
+        assert_eq!(b.jump(0), Some((2, 0)));
+        // A line is in the jump list once, at its newest place
+        b.push_jump(0, 0);
+        b.push_jump(2, 1);
+        assert_eq!(b.jump_count(), 2);
+        assert_eq!(b.jump(1), Some((2, 1)));
+    }
 }
@@ -0,0 +1,206 @@
+//! The buffer list: every file loaded for editing has a number, and all but
+//! the one being edited wait here with their cursor and undo history until
+//! `:bn`, `:b N` or `:e` switches back to them.
+
+use std::path::{Path, PathBuf};
+
+use crate::buffer::{Buffer, LineEnding};
+use crate::encoding::Encoding;
+use crate::undo::UndoTree;
+
+/// What the editor keeps about a file while another one is being edited.
+pub struct FileState {
+    pub buf: Buffer,
+    pub filename: Option<PathBuf>,
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    pub dirty: bool,
+    pub undo: UndoTree,
+    /// Cursor line and display column when the file was left.
+    pub cursor: (usize, usize),
+    /// First line the window showed.
+    pub top: usize,
+    pub jump_idx: usize,
+}
+
+impl FileState {
+    /// A new buffer with no file and no text.
+    pub fn empty() -> Self {
+        Self {
+            buf: Buffer::default(),
+            filename: None,
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            dirty: false,
+            undo: UndoTree::default(),
+            cursor: (0, 0),
+            top: 0,
+            jump_idx: 0,
+        }
+    }
+}
+
+pub struct BufferList {
+    /// Number of the buffer being edited. Its state lives in the editor.
+    pub current: usize,
+    /// The buffer edited before the current one (`#` in `:ls`).
+    pub alternate: Option<usize>,
+    /// The other buffers, by number.
+    others: Vec<(usize, FileState)>,
+    /// Highest number handed out so far; numbers are never reused.
+    last: usize,
+}
+
+impl Default for BufferList {
+    fn default() -> Self {
+        Self {
+            current: 1,
+            alternate: None,
+            others: Vec::new(),
+            last: 1,
+        }
+    }
+}
+
+impl BufferList {
+    /// A number for a new buffer.
+    pub fn new_number(&mut self) -> usize {
+        self.last += 1;
+        self.last
+    }
+
+    /// Numbers of all buffers, the current one included, in order.
+    pub fn numbers(&self) -> Vec<usize> {
+        let mut all: Vec<usize> = self.others.iter().map(|(n, _)| *n).collect();
+        all.push(self.current);
+        all.sort_unstable();
+        all
+    }
+
+    pub fn get(&self, n: usize) -> Option<&FileState> {
+        self.others.iter().find(|(m, _)| *m == n).map(|(_, s)| s)
+    }
+
+    pub fn get_mut(&mut self, n: usize) -> Option<&mut FileState> {
+        self.others
+            .iter_mut()
+            .find(|(m, _)| *m == n)
+            .map(|(_, s)| s)
+    }
+
+    /// Takes buffer `n` out of the list, to be edited or deleted.
+    pub fn take(&mut self, n: usize) -> Option<FileState> {
+        let i = self.others.iter().position(|(m, _)| *m == n)?;
+        Some(self.others.remove(i).1)
+    }
+
+    /// Keeps `state` as buffer `n`.
+    pub fn put(&mut self, n: usize, state: FileState) {
+        let i = self.others.partition_point(|(m, _)| *m < n);
+        self.others.insert(i, (n, state));
+    }
+
+    /// The other buffers, in number order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &FileState)> {
+        self.others.iter().map(|(n, s)| (*n, s))
+    }
+
+    /// The buffer `count` places after (or before) the current one,
+    /// wrapping around the ends of the list.
+    pub fn cycle(&self, forward: bool, count: usize) -> usize {
+        let all = self.numbers();
+        let i = all.iter().position(|&n| n == self.current).unwrap_or(0);
+        let len = all.len();
+        let step = count % len;
+        let j = if forward {
+            (i + step) % len
+        } else {
+            (i + len - step) % len
+        };
+        all[j]
+    }
+
+    /// Another buffer editing the file at `path`.
+    pub fn find_path(&self, path: &Path) -> Option<usize> {
+        self.iter()
+            .find(|(_, s)| s.filename.as_deref().is_some_and(|f| same_file(f, path)))
+            .map(|(n, _)| n)
+    }
+
+    /// `:b name`: the one buffer whose file name contains `name`, preferring
+    /// an exact match. `current` is the file being edited.
+    pub fn find_name(&self, name: &str, current: Option<&Path>) -> Result<usize, String> {
+        let all = std::iter::once((self.current, current))
+            .chain(self.iter().map(|(n, s)| (n, s.filename.as_deref())));
+        let mut exact = Vec::new();
+        let mut partial = Vec::new();
+        for (n, path) in all {
+            let Some(path) = path else {
+                continue;
+            };
+            let full = path.to_string_lossy();
+            let file = path.file_name().map(|f| f.to_string_lossy());
+            if full == name || file.is_some_and(|f| f == name) {
+                exact.push(n);
+            } else if full.contains(name) {
+                partial.push(n);
+            }
+        }
+        let found = if exact.is_empty() { partial } else { exact };
+        match found[..] {
+            [n] => Ok(n),
+            [] => Err(format!("No matching buffer for {}", name)),
+            _ => Err(format!("More than one match for {}", name)),
+        }
+    }
+}
+
+/// Whether `a` and `b` name the same file, even if spelled differently.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str) -> FileState {
+        FileState {
+            buf: Buffer::default(),
+            filename: Some(PathBuf::from(name)),
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            dirty: false,
+            undo: UndoTree::default(),
+            cursor: (0, 0),
+            top: 0,
+            jump_idx: 0,
+        }
+    }
+
+    #[test]
+    fn numbers_cycle_and_names() {
+        let mut list = BufferList::default();
+        for name in ["src/main.rs", "src/app.rs", "README.md"] {
+            let n = list.new_number();
+            list.put(n, state(name));
+        }
+        assert_eq!(list.numbers(), vec![1, 2, 3, 4]);
+        assert_eq!(list.cycle(true, 1), 2);
+        assert_eq!(list.cycle(false, 1), 4);
+        assert_eq!(list.cycle(true, 5), 2);
+        list.take(3);
+        assert_eq!(list.numbers(), vec![1, 2, 4]);
+
+        let current = Some(Path::new("src/editor.rs"));
+        assert_eq!(list.find_name("READ", current), Ok(4));
+        assert_eq!(list.find_name("editor", current), Ok(1));
+        assert_eq!(list.find_name("main.rs", current), Ok(2));
+        assert!(list.find_name("src", current).is_err());
+        assert!(list.find_name("nothing", current).is_err());
+        assert_eq!(list.find_path(Path::new("README.md")), Some(4));
+    }
+}
@@ -0,0 +1,225 @@
+//! The system clipboard behind the `"+` and `"*` registers.
+//!
+//! Copies are sent to the terminal as OSC 52 escape sequences, which reach the
+//! desktop clipboard even over SSH and through tmux. Terminals can't be read
+//! that way, so pasting needs an external command (e.g. `xclip -o`); an
+//! external copy command can be configured as well.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a copy or paste command may take before it is killed, so that
+/// one that hangs (e.g. `xclip` without a display) doesn't freeze the editor.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct SystemClipboard {
+    /// Command that reads the text to copy from stdin, e.g. `wl-copy`.
+    pub copy_cmd: Option<String>,
+    /// Command that prints the clipboard, e.g. `wl-paste -n`.
+    pub paste_cmd: Option<String>,
+    /// Send copies to the terminal with OSC 52.
+    pub osc52: bool,
+    /// OSC 52 sequence waiting to be written by the UI.
+    osc52_pending: Option<String>,
+}
+
+impl SystemClipboard {
+    pub fn new(copy_cmd: Option<String>, paste_cmd: Option<String>, osc52: bool) -> Self {
+        Self {
+            copy_cmd,
+            paste_cmd,
+            osc52,
+            osc52_pending: None,
+        }
+    }
+
+    /// Copies `text` with every configured method. Fails only if the copy
+    /// command does; OSC 52 can't report errors.
+    pub fn copy(&mut self, text: &str, primary: bool) -> io::Result<()> {
+        if self.osc52 {
+            let inside_tmux = std::env::var_os("TMUX").is_some();
+            self.osc52_pending = Some(osc52_sequence(text, primary, inside_tmux));
+        }
+        match &self.copy_cmd {
+            Some(cmd) => run_copy(cmd, text, COMMAND_TIMEOUT),
+            None => Ok(()),
+        }
+    }
+
+    /// Text from the paste command, or `None` if there is none configured.
+    pub fn paste(&self) -> Option<io::Result<String>> {
+        self.paste_cmd
+            .as_deref()
+            .map(|cmd| run_paste(cmd, COMMAND_TIMEOUT))
+    }
+
+    /// The OSC 52 sequence for the last copy, for the UI to write out.
+    pub fn take_osc52(&mut self) -> Option<String> {
+        self.osc52_pending.take()
+    }
+}
+
+/// Splits a configured command line on whitespace into program and args.
+fn command(cmd: &str) -> io::Result<Command> {
+    let mut parts = cmd.split_whitespace();
+    let prog = parts
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    let mut c = Command::new(prog);
+    c.args(parts);
+    Ok(c)
+}
+
+fn run_copy(cmd: &str, text: &str, timeout: Duration) -> io::Result<()> {
+    let mut child = command(cmd)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    // Write on a thread, so that a command that never reads can be left
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("no stdin"))?;
+    let text = text.to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // Dropping stdin closes it, ending the command's input
+        let _ = tx.send(stdin.write_all(text.as_bytes()));
+    });
+    let Ok(written) = rx.recv_timeout(timeout) else {
+        return Err(timed_out(&mut child, cmd));
+    };
+    written?;
+    wait(&mut child, cmd, deadline)
+}
+
+fn run_paste(cmd: &str, timeout: Duration) -> io::Result<String> {
+    let mut child = command(cmd)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    // Read on a thread, so that a command that never finishes can be left
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("no stdout"))?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = tx.send(stdout.read_to_end(&mut out).map(|_| out));
+    });
+    let Ok(out) = rx.recv_timeout(timeout) else {
+        return Err(timed_out(&mut child, cmd));
+    };
+    let out = out?;
+    wait(&mut child, cmd, deadline)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Waits for `child` to exit successfully, killing it at `deadline`.
+fn wait(child: &mut Child, cmd: &str, deadline: Instant) -> io::Result<()> {
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            return Err(timed_out(child, cmd));
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    if !status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", cmd, status)));
+    }
+    Ok(())
+}
+
+fn timed_out(child: &mut Child, cmd: &str) -> io::Error {
+    let _ = child.kill();
+    let _ = child.wait();
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", cmd))
+}
+
+/// `ESC ] 52 ; c ; <base64> BEL`, wrapped in a DCS passthrough inside tmux.
+/// `primary` targets the primary selection (`"*`) instead of the clipboard.
+fn osc52_sequence(text: &str, primary: bool, inside_tmux: bool) -> String {
+    let target = if primary { 'p' } else { 'c' };
+    let seq = format!("\x1b]52;{};{}\x07", target, base64(text.as_bytes()));
+    if inside_tmux {
+        format!("\x1bPtmux;{}\x1b\\", seq.replace('\x1b', "\x1b\x1b"))
+    } else {
+        seq
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn osc52_encoding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(osc52_sequence("hi", false, false), "\x1b]52;c;aGk=\x07");
+        assert_eq!(
+            osc52_sequence("hi", true, true),
+            "\x1bPtmux;\x1b\x1b]52;p;aGk=\x07\x1b\\"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paste_command_that_hangs_is_killed() {
+        let timeout = Duration::from_millis(100);
+        assert_eq!(run_paste("echo hi", timeout).unwrap(), "hi\n");
+        let start = Instant::now();
+        let err = run_paste("sleep 10", timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_command_that_hangs_is_killed() {
+        let timeout = Duration::from_millis(100);
+        run_copy("cat", "hi", timeout).unwrap();
+        assert!(run_copy("false", "hi", timeout).is_err());
+        let start = Instant::now();
+        let err = run_copy("sleep 10", "hi", timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Even when the pipe fills up because the command never reads
+        let err = run_copy("sleep 10", &"x".repeat(1 << 20), timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,357 @@
+/// Character encoding of a file on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 preceded by a byte order mark.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeError {
+    pub ch: char,
+    pub line: usize,
+    pub encoding: Encoding,
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(b) = escaped_byte(self.ch) {
+            return write!(
+                f,
+                "cannot encode byte 0x{:02x} (line {}) as {}",
+                b,
+                self.line + 1,
+                self.encoding.name()
+            );
+        }
+        write!(
+            f,
+            "cannot encode '{}' (line {}) as {}",
+            self.ch,
+            self.line + 1,
+            self.encoding.name()
+        )
+    }
+}
+
+/// Bytes that cannot be decoded are kept in the text as characters from the
+/// last 256 code points of plane 16 (private use), one per byte, and written
+/// back verbatim on save. A file that really contains one of those characters
+/// has it kept as its encoded bytes, so that it is written back unchanged.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+/// Returns the raw byte represented by `c` if it is an escaped undecodable byte.
+pub fn escaped_byte(c: char) -> Option<u8> {
+    let v = c as u32;
+    if (ESCAPE_BASE..ESCAPE_BASE + 0x100).contains(&v) {
+        Some((v - ESCAPE_BASE) as u8)
+    } else {
+        None
+    }
+}
+
+fn escape_byte(b: u8) -> char {
+    char::from_u32(ESCAPE_BASE + b as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Appends decoded text, escaping the bytes of any char that would be taken
+/// for an escaped byte on save.
+fn push_decoded(out: &mut String, s: &str) {
+    if !s.chars().any(|c| escaped_byte(c).is_some()) {
+        out.push_str(s);
+        return;
+    }
+    let mut tmp = [0u8; 4];
+    for c in s.chars() {
+        if escaped_byte(c).is_some() {
+            out.extend(c.encode_utf8(&mut tmp).bytes().map(escape_byte));
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+impl Encoding {
+    /// Name used by `:set fileencoding` and the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Bom => "utf-8-bom",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Encoding::Utf8Bom),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" | "utf-16" | "utf16" => Some(Encoding::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    fn is_utf16(self) -> bool {
+        matches!(self, Encoding::Utf16Le | Encoding::Utf16Be)
+    }
+}
+
+/// Decodes file contents, sniffing a BOM first and then validating UTF-8.
+///
+/// Text that is not valid UTF-8 is read as UTF-8 with escaped bytes when it
+/// contains at least one valid multi-byte sequence, and as Latin-1 otherwise.
+pub fn decode(bytes: &[u8]) -> (String, Encoding) {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return (decode_utf8_lossless(rest), Encoding::Utf8Bom);
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return (decode_utf16(rest, u16::from_le_bytes), Encoding::Utf16Le);
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return (decode_utf16(rest, u16::from_be_bytes), Encoding::Utf16Be);
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return (decode_utf8_lossless(bytes), Encoding::Utf8);
+    }
+    let text = decode_utf8_lossless(bytes);
+    if text
+        .chars()
+        .any(|c| !c.is_ascii() && escaped_byte(c).is_none())
+    {
+        (text, Encoding::Utf8)
+    } else {
+        (bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1)
+    }
+}
+
+fn decode_utf8_lossless(mut bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => {
+                push_decoded(&mut out, s);
+                return out;
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                // The prefix was just validated
+                push_decoded(
+                    &mut out,
+                    std::str::from_utf8(&bytes[..valid]).unwrap_or_default(),
+                );
+                let bad = e.error_len().unwrap_or(bytes.len() - valid);
+                out.extend(bytes[valid..valid + bad].iter().map(|&b| escape_byte(b)));
+                bytes = &bytes[valid + bad..];
+            }
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let mut out = String::with_capacity(bytes.len() / 2);
+    let pairs = bytes.chunks_exact(2);
+    let tail = pairs.remainder();
+    let units: Vec<([u8; 2], u16)> = pairs
+        .map(|p| {
+            let raw = [p[0], p[1]];
+            (raw, unit(raw))
+        })
+        .collect();
+    let mut i = 0;
+    while i < units.len() {
+        let (raw, u) = units[i];
+        let next = units.get(i + 1).map(|&(_, n)| n);
+        match (u, next) {
+            (0xD800..=0xDBFF, Some(lo @ 0xDC00..=0xDFFF)) => {
+                let c = 0x10000 + (((u as u32) - 0xD800) << 10) + ((lo as u32) - 0xDC00);
+                if c >= ESCAPE_BASE {
+                    // Would be taken for escaped bytes: keep its own
+                    out.extend(raw.iter().chain(&units[i + 1].0).map(|&b| escape_byte(b)));
+                } else {
+                    out.push(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                i += 2;
+            }
+            (0xD800..=0xDFFF, _) => {
+                // Unpaired surrogate: keep its bytes
+                out.push(escape_byte(raw[0]));
+                out.push(escape_byte(raw[1]));
+                i += 1;
+            }
+            _ => {
+                out.push(char::from_u32(u as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+                i += 1;
+            }
+        }
+    }
+    out.extend(tail.iter().map(|&b| escape_byte(b)));
+    out
+}
+
+/// Encodes `text` for writing. Escaped bytes are emitted verbatim in every encoding.
+pub fn encode(text: &str, enc: Encoding) -> Result<Vec<u8>, EncodeError> {
+    let mut out = Vec::with_capacity(text.len());
+    match enc {
+        Encoding::Utf8Bom => out.extend_from_slice(&[0xEF, 0xBB, 0xBF]),
+        Encoding::Utf16Le => out.extend_from_slice(&[0xFF, 0xFE]),
+        Encoding::Utf16Be => out.extend_from_slice(&[0xFE, 0xFF]),
+        Encoding::Utf8 | Encoding::Latin1 => {}
+    }
+    let mut line = 0usize;
+    let mut units = [0u16; 2];
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+        if let Some(b) = escaped_byte(c) {
+            out.push(b);
+            continue;
+        }
+        match enc {
+            Encoding::Utf8 | Encoding::Utf8Bom => {
+                let mut tmp = [0u8; 4];
+                out.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            }
+            Encoding::Utf16Le => {
+                for u in c.encode_utf16(&mut units) {
+                    out.extend_from_slice(&u.to_le_bytes());
+                }
+            }
+            Encoding::Utf16Be => {
+                for u in c.encode_utf16(&mut units) {
+                    out.extend_from_slice(&u.to_be_bytes());
+                }
+            }
+            Encoding::Latin1 => {
+                if (c as u32) > 0xFF {
+                    return Err(EncodeError {
+                        ch: c,
+                        line,
+                        encoding: enc,
+                    });
+                }
+                out.push(c as u8);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Checks that `text`, read as `from`, can be written as `to`. Escaped bytes
+/// are code units of the encoding they were read in, so they cannot be
+/// carried between UTF-8 and UTF-16.
+pub fn check_conversion(text: &str, from: Encoding, to: Encoding) -> Result<(), EncodeError> {
+    if from.is_utf16() != to.is_utf16() {
+        let escaped = text.split('\n').enumerate().find_map(|(line, l)| {
+            l.chars()
+                .find(|&c| escaped_byte(c).is_some())
+                .map(|ch| (ch, line))
+        });
+        if let Some((ch, line)) = escaped {
+            return Err(EncodeError {
+                ch,
+                line,
+                encoding: to,
+            });
+        }
+    }
+    encode(text, to).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_utf8_is_detected() {
+        let (s, enc) = decode("żółw\n".as_bytes());
+        assert_eq!(enc, Encoding::Utf8);
+        assert_eq!(s, "żółw\n");
+    }
+
+    #[test]
+    fn latin1_fallback_round_trips() {
+        let bytes = b"caf\xe9 = \xa3\n".to_vec();
+        let (s, enc) = decode(&bytes);
+        assert_eq!(enc, Encoding::Latin1);
+        assert_eq!(s, "café = £\n");
+        assert_eq!(encode(&s, enc).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid_bytes_in_utf8_are_kept() {
+        let bytes = b"\xc5\xbc\xff ok\xe2\x82".to_vec();
+        let (s, enc) = decode(&bytes);
+        assert_eq!(enc, Encoding::Utf8);
+        assert!(s.starts_with('ż'));
+        assert_eq!(s.chars().filter(|&c| escaped_byte(c).is_some()).count(), 3);
+        assert_eq!(encode(&s, enc).unwrap(), bytes);
+    }
+
+    #[test]
+    fn private_use_chars_like_escapes_round_trip() {
+        let text = "a\u{10FF41}b\n";
+        let bytes = text.as_bytes().to_vec();
+        let (s, enc) = decode(&bytes);
+        assert_eq!(enc, Encoding::Utf8);
+        assert_eq!(encode(&s, enc).unwrap(), bytes);
+        let mixed = [&bytes[..], b"\xff"].concat();
+        let (s, enc) = decode(&mixed);
+        assert_eq!(encode(&s, enc).unwrap(), mixed);
+        let mut utf16 = vec![0xFF, 0xFE];
+        for u in text.encode_utf16() {
+            utf16.extend_from_slice(&u.to_le_bytes());
+        }
+        let (s, enc) = decode(&utf16);
+        assert_eq!(enc, Encoding::Utf16Le);
+        assert_eq!(encode(&s, enc).unwrap(), utf16);
+    }
+
+    #[test]
+    fn utf16_with_bom_round_trips() {
+        for enc in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encode("a😄\n", enc).unwrap();
+            let (s, got) = decode(&bytes);
+            assert_eq!(got, enc);
+            assert_eq!(s, "a😄\n");
+            assert_eq!(encode(&s, enc).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn utf16_unpaired_surrogate_and_odd_tail_are_kept() {
+        let bytes = vec![0xFF, 0xFE, b'a', 0, 0x00, 0xD8, b'b', 0, 0x7F];
+        let (s, enc) = decode(&bytes);
+        assert_eq!(enc, Encoding::Utf16Le);
+        assert_eq!(encode(&s, enc).unwrap(), bytes);
+    }
+
+    #[test]
+    fn escaped_bytes_do_not_convert_to_utf16() {
+        let (s, enc) = decode(b"\xc5\xbc\nok\xff\n");
+        assert_eq!(enc, Encoding::Utf8);
+        let err = check_conversion(&s, enc, Encoding::Utf16Le).unwrap_err();
+        assert_eq!(err.line, 1);
+        assert_eq!(
+            err.to_string(),
+            "cannot encode byte 0xff (line 2) as utf-16le"
+        );
+        assert!(check_conversion(&s, enc, Encoding::Utf8Bom).is_ok());
+        let (s, enc) = decode(&[0xFF, 0xFE, 0x00, 0xD8]);
+        assert!(check_conversion(&s, enc, Encoding::Utf8).is_err());
+        assert!(check_conversion(&s, enc, Encoding::Utf16Be).is_ok());
+    }
+
+    #[test]
+    fn latin1_rejects_wide_chars() {
+        let err = encode("ok\nżółw", Encoding::Latin1).unwrap_err();
+        assert_eq!(err.ch, 'ż');
+        assert_eq!(err.line, 1);
+    }
+}
@@ -0,0 +1,447 @@
+//! Parsing for ex command lines: line ranges and `:substitute` arguments.
+
+use crate::regex::Captures;
+
+/// Inclusive range of 0-based line numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// What addresses are resolved against. Lines are 0-based.
+pub trait Addresses {
+    fn current_line(&self) -> usize;
+    fn last_line(&self) -> usize;
+    /// Line of mark `name` (`a`-`z`, `<`, `>`, ...), if set.
+    fn mark_line(&self, name: char) -> Option<usize>;
+    /// First line after (or before, searching backward) `from` with a match
+    /// for `pat`, wrapping around. An empty pattern means the last search.
+    fn search_line(&self, pat: &str, from: usize, backward: bool) -> Result<usize, String>;
+}
+
+/// Parses an optional leading range and returns it with the rest of the
+/// command. Addresses are `N`, `.`, `$`, `'x` (mark), `/pat/`, `?pat?`, each
+/// optionally followed by `+N`/`-N`; `%` is the whole buffer, and `a;b`
+/// evaluates `b` from `a`. An address past the last line, or `0`, is an
+/// `Invalid range` error, except that a bare address (`:999` as a jump) is
+/// clamped into the buffer.
+pub fn parse_range<'a>(
+    s: &'a str,
+    ctx: &impl Addresses,
+) -> Result<(Option<LineRange>, &'a str), String> {
+    let last = ctx.last_line();
+    if let Some(rest) = s.strip_prefix('%') {
+        return Ok((
+            Some(LineRange {
+                start: 0,
+                end: last,
+            }),
+            rest,
+        ));
+    }
+    let (first, mut rest) = parse_address(s, ctx, ctx.current_line())?;
+    let Some(first) = first else {
+        return Ok((None, s));
+    };
+    let mut end = first;
+    if let Some(sep) = rest.chars().next().filter(|c| *c == ',' || *c == ';') {
+        let base = if sep == ';' {
+            to_line(first, last)?
+        } else {
+            ctx.current_line()
+        };
+        let (second, r) = parse_address(&rest[1..], ctx, base)?;
+        end = second.unwrap_or(base as isize + 1);
+        rest = r;
+    }
+    let (start, end) = if rest.trim().is_empty() {
+        let clamp = |l: isize| (l - 1).clamp(0, last as isize) as usize;
+        (clamp(first), clamp(end))
+    } else {
+        (to_line(first, last)?, to_line(end, last)?)
+    };
+    let (start, end) = if start <= end {
+        (start, end)
+    } else {
+        (end, start)
+    };
+    Ok((Some(LineRange { start, end }), rest))
+}
+
+/// Parses the destination of `:m` and `:t`: the line to put text after, as
+/// a 1-based line number where `0` means above the first line.
+pub fn parse_target(s: &str, ctx: &impl Addresses) -> Result<usize, String> {
+    let (addr, rest) = parse_address(s.trim(), ctx, ctx.current_line())?;
+    if !rest.trim().is_empty() {
+        return Err(format!("Trailing characters: {}", rest.trim()));
+    }
+    let addr = addr.ok_or_else(|| "Missing destination".to_string())?;
+    if addr < 0 || addr > ctx.last_line() as isize + 1 {
+        return Err("Invalid range".to_string());
+    }
+    Ok(addr as usize)
+}
+
+/// 1-based line number to a 0-based line, which must be in the buffer.
+fn to_line(lnum: isize, last: usize) -> Result<usize, String> {
+    if lnum < 1 || lnum > last as isize + 1 {
+        return Err("Invalid range".to_string());
+    }
+    Ok(lnum as usize - 1)
+}
+
+/// One address as a 1-based line number: an optional base followed by
+/// `+N`/`-N` offsets. A bare offset is relative to `base`, as is a
+/// missing base when there are offsets.
+fn parse_address<'a>(
+    s: &'a str,
+    ctx: &impl Addresses,
+    base: usize,
+) -> Result<(Option<isize>, &'a str), String> {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = |s: &str| s.parse::<isize>().map_err(|_| "Invalid range".to_string());
+    let base = base as isize + 1;
+    let mut rest = s;
+    let mut line: Option<isize> = None;
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some('.') => {
+            line = Some(base);
+            rest = chars.as_str();
+        }
+        Some('$') => {
+            line = Some(ctx.last_line() as isize + 1);
+            rest = chars.as_str();
+        }
+        Some('\'') => {
+            let name = chars
+                .next()
+                .ok_or_else(|| "Missing mark name".to_string())?;
+            let l = ctx
+                .mark_line(name)
+                .ok_or_else(|| format!("Mark not set: {}", name))?;
+            line = Some(l as isize + 1);
+            rest = chars.as_str();
+        }
+        Some(delim @ ('/' | '?')) => {
+            let (pat, r) = split_delimited(chars.as_str(), delim);
+            let from = (base - 1) as usize;
+            line = Some(ctx.search_line(&pat, from, delim == '?')? as isize + 1);
+            rest = r.unwrap_or("");
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let n = digits(rest);
+            line = Some(number(&rest[..n])?);
+            rest = &rest[n..];
+        }
+        _ => {}
+    }
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        rest = &rest[1..];
+        let n = digits(rest);
+        let off = if n == 0 { 1 } else { number(&rest[..n])? };
+        rest = &rest[n..];
+        let l = line.unwrap_or(base);
+        line = Some(if sign == '+' { l + off } else { l - off });
+    }
+    Ok((line, rest))
+}
+
+/// Parsed `:s/pattern/replacement/flags`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubstituteCmd {
+    pub pattern: String,
+    pub replacement: String,
+    /// `g`: every match on a line, not just the first.
+    pub global: bool,
+    /// `c`: ask before each replacement.
+    pub confirm: bool,
+    /// `n`: only count the matches.
+    pub count_only: bool,
+    /// `i`/`I`: override the `ignorecase` option.
+    pub ignore_case: Option<bool>,
+}
+
+/// Parses the argument of `:s`. Any non-alphanumeric char other than `\`,
+/// `"` and `|` can be the delimiter; trailing delimiters may be omitted.
+pub fn parse_substitute(arg: &str) -> Result<SubstituteCmd, String> {
+    let mut chars = arg.chars();
+    let delim = match chars.next() {
+        Some(d) if is_delimiter(d) => d,
+        _ => return Err("Invalid substitute delimiter".to_string()),
+    };
+    let rest = chars.as_str();
+    let (pattern, rest) = split_delimited(rest, delim);
+    let (replacement, flags) = split_delimited(rest.unwrap_or(""), delim);
+    let mut cmd = SubstituteCmd {
+        pattern,
+        replacement,
+        global: false,
+        confirm: false,
+        count_only: false,
+        ignore_case: None,
+    };
+    for f in flags.unwrap_or("").trim().chars() {
+        match f {
+            'g' => cmd.global = !cmd.global,
+            'c' => cmd.confirm = true,
+            'n' => cmd.count_only = true,
+            'i' => cmd.ignore_case = Some(true),
+            'I' => cmd.ignore_case = Some(false),
+            'e' | '&' => {}
+            _ => return Err(format!("Trailing characters: {}", f)),
+        }
+    }
+    Ok(cmd)
+}
+
+/// Parses the argument of `:g` and `:v`: `[!]/pattern/command`, where the
+/// delimiter follows the `:s` rules. Returns whether `!` (invert) was given,
+/// the pattern and the command to run, which may be empty.
+pub fn parse_global(arg: &str) -> Result<(bool, String, &str), String> {
+    let (bang, arg) = match arg.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let mut chars = arg.trim_start().chars();
+    let delim = match chars.next() {
+        Some(d) if is_delimiter(d) => d,
+        _ => return Err("Regular expression missing from :global".to_string()),
+    };
+    let (pattern, rest) = split_delimited(chars.as_str(), delim);
+    Ok((bang, pattern, rest.unwrap_or("").trim_start()))
+}
+
+fn is_delimiter(c: char) -> bool {
+    !c.is_alphanumeric() && !matches!(c, '\\' | '"' | '|' | ' ')
+}
+
+/// Splits `s` at the first unescaped `delim`. An escaped delimiter loses its
+/// backslash; other escapes are kept for the regex or replacement parser.
+fn split_delimited(s: &str, delim: char) -> (String, Option<&str>) {
+    let mut out = String::new();
+    let mut it = s.char_indices();
+    while let Some((i, c)) = it.next() {
+        if c == delim {
+            return (out, Some(&s[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match it.next() {
+                Some((_, e)) if e == delim => out.push(e),
+                Some((_, e)) => {
+                    out.push('\\');
+                    out.push(e);
+                }
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    (out, None)
+}
+
+/// Replaces each unescaped `~` in a replacement with the previous replacement.
+pub fn expand_tilde(replacement: &str, previous: &str) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => out.push_str(previous),
+            '\\' => {
+                out.push('\\');
+                if let Some(e) = chars.next() {
+                    out.push(e);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+fn push_cased(out: &mut String, s: &str, once: &mut Option<Case>, until_end: Option<Case>) {
+    for c in s.chars() {
+        match once.take().or(until_end) {
+            Some(Case::Upper) => out.extend(c.to_uppercase()),
+            Some(Case::Lower) => out.extend(c.to_lowercase()),
+            None => out.push(c),
+        }
+    }
+}
+
+/// Builds the text for one replacement. `&` and `\0` insert the whole match,
+/// `\1`..`\9` groups, `\r` a line break, `\n` a NUL (as in Vim), `\t` a tab;
+/// `\u`/`\l` change the case of the next char and `\U`/`\L` of everything up
+/// to `\E`/`\e`.
+pub fn expand_replacement(
+    template: &str,
+    caps: &Captures,
+    text: impl Fn(usize, usize) -> String,
+) -> String {
+    let mut out = String::new();
+    let mut once: Option<Case> = None;
+    let mut until_end: Option<Case> = None;
+    let mut chars = template.chars();
+    let mut buf = [0u8; 4];
+    while let Some(c) = chars.next() {
+        let piece: String = match c {
+            '&' => caps.group(0).map(|(s, e)| text(s, e)).unwrap_or_default(),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => {
+                    let i = d as usize - '0' as usize;
+                    caps.group(i).map(|(s, e)| text(s, e)).unwrap_or_default()
+                }
+                Some('r') => "\n".to_string(),
+                Some('n') => "\0".to_string(),
+                Some('t') => "\t".to_string(),
+                Some('u') => {
+                    once = Some(Case::Upper);
+                    continue;
+                }
+                Some('l') => {
+                    once = Some(Case::Lower);
+                    continue;
+                }
+                Some('U') => {
+                    until_end = Some(Case::Upper);
+                    continue;
+                }
+                Some('L') => {
+                    until_end = Some(Case::Lower);
+                    continue;
+                }
+                Some('E') | Some('e') => {
+                    until_end = None;
+                    continue;
+                }
+                Some(e) => e.encode_utf8(&mut buf).to_string(),
+                None => "\\".to_string(),
+            },
+            _ => c.encode_utf8(&mut buf).to_string(),
+        };
+        push_cased(&mut out, &piece, &mut once, until_end);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::Regex;
+
+    struct Lines {
+        cur: usize,
+        text: Vec<&'static str>,
+    }
+
+    impl Addresses for Lines {
+        fn current_line(&self) -> usize {
+            self.cur
+        }
+        fn last_line(&self) -> usize {
+            self.text.len() - 1
+        }
+        fn mark_line(&self, name: char) -> Option<usize> {
+            (name == 'a').then_some(7)
+        }
+        fn search_line(&self, pat: &str, from: usize, backward: bool) -> Result<usize, String> {
+            let n = self.text.len();
+            (1..=n)
+                .map(|i| {
+                    if backward {
+                        (from + n - i) % n
+                    } else {
+                        (from + i) % n
+                    }
+                })
+                .find(|&y| self.text[y].contains(pat))
+                .ok_or_else(|| format!("Pattern not found: {}", pat))
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let ctx = Lines {
+            cur: 4,
+            text: vec!["a", "foo", "b", "c", "d", "foo", "e", "f", "g", "h"],
+        };
+        let r = |s| parse_range(s, &ctx).unwrap();
+        let lr = |start, end| Some(LineRange { start, end });
+        assert_eq!(r("%s"), (lr(0, 9), "s"));
+        assert_eq!(r("3,5d"), (lr(2, 4), "d"));
+        assert_eq!(r(".,$"), (lr(4, 9), ""));
+        assert_eq!(r(".+2"), (lr(6, 6), ""));
+        assert_eq!(r("-"), (lr(3, 3), ""));
+        assert_eq!(r("8,2"), (lr(1, 7), ""));
+        assert_eq!(r("'a,$-1y"), (lr(7, 8), "y"));
+        assert_eq!(r("/foo/"), (lr(5, 5), ""));
+        assert_eq!(r("?foo?+1,/foo/d"), (lr(2, 5), "d"));
+        assert_eq!(r("2;+2"), (lr(1, 3), ""));
+        assert_eq!(r("s/a/b/"), (None, "s/a/b/"));
+        assert!(parse_range("'b", &ctx).is_err());
+        assert!(parse_range("/zzz/", &ctx).is_err());
+        assert_eq!(r("99"), (lr(9, 9), ""));
+        assert_eq!(r("0"), (lr(0, 0), ""));
+        assert!(parse_range("11d", &ctx).is_err());
+        assert!(parse_range("1,99d", &ctx).is_err());
+        assert!(parse_range("0d", &ctx).is_err());
+        assert!(parse_range("1;+20y", &ctx).is_err());
+        assert!(parse_target("12", &ctx).is_err());
+        assert_eq!(parse_target("0", &ctx), Ok(0));
+        assert_eq!(parse_target("$", &ctx), Ok(10));
+        assert_eq!(parse_target(".-1", &ctx), Ok(4));
+    }
+
+    #[test]
+    fn substitute_arguments() {
+        let cmd = parse_substitute("#a\\#b#x/y#gc").unwrap();
+        assert_eq!(cmd.pattern, "a#b");
+        assert_eq!(cmd.replacement, "x/y");
+        assert!(cmd.global && cmd.confirm && !cmd.count_only);
+        let cmd = parse_substitute("/\\(a\\)/\\1").unwrap();
+        assert_eq!(cmd.pattern, "\\(a\\)");
+        assert_eq!(cmd.replacement, "\\1");
+        assert!(parse_substitute("/a/b/z").is_err());
+        assert!(parse_substitute("abc").is_err());
+    }
+
+    #[test]
+    fn global_arguments() {
+        assert_eq!(
+            parse_global("/err\\/x/s/a/b/").unwrap(),
+            (false, "err/x".to_string(), "s/a/b/")
+        );
+        assert_eq!(
+            parse_global("!#^$# d").unwrap(),
+            (true, "^$".to_string(), "d")
+        );
+        assert_eq!(parse_global("/x").unwrap(), (false, "x".to_string(), ""));
+        assert!(parse_global("").is_err());
+    }
+
+    #[test]
+    fn replacement_expansion() {
+        let text = "hello world";
+        let re = Regex::new("\\(\\w\\+\\) \\(\\w\\+\\)", false).unwrap();
+        let caps = re.search(text.chars(), 0, None, 0).unwrap();
+        let get = |s: usize, e: usize| text.chars().skip(s).take(e - s).collect::<String>();
+        assert_eq!(expand_replacement("\\2 \\1", &caps, get), "world hello");
+        assert_eq!(expand_replacement("[&]", &caps, get), "[hello world]");
+        assert_eq!(
+            expand_replacement("\\u\\1 \\U\\2\\E!", &caps, get),
+            "Hello WORLD!"
+        );
+        assert_eq!(expand_replacement("\\L\\uABC", &caps, get), "Abc");
+        assert_eq!(expand_replacement("a\\rb\\&", &caps, get), "a\nb&");
+        assert_eq!(expand_replacement("a\\nb", &caps, get), "a\0b");
+        assert_eq!(expand_tilde("<~>\\~", "prev"), "<prev>\\~");
+    }
+}
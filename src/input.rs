@@ -0,0 +1,129 @@
+//! Key input for the main loop and the prompts. Keys of a running macro are
+//! fed in before anything from the terminal, and keys read from the terminal
+//! are recorded while `q` is active.
+//!
+//! Macros live in registers as text with one char per key: printable keys as
+//! themselves, Ctrl-letters as control codes, Esc/Enter/Backspace/Tab as
+//! `\x1b`/`\r`/`\x7f`/`\t`, and the remaining special keys as private-use
+//! chars.
+
+use std::io;
+use std::time::Duration;
+
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+
+use crate::editor::Editor;
+
+const SPECIAL_KEYS: [(KeyCode, char); 9] = [
+    (KeyCode::Up, '\u{e000}'),
+    (KeyCode::Down, '\u{e001}'),
+    (KeyCode::Left, '\u{e002}'),
+    (KeyCode::Right, '\u{e003}'),
+    (KeyCode::Home, '\u{e004}'),
+    (KeyCode::End, '\u{e005}'),
+    (KeyCode::PageUp, '\u{e006}'),
+    (KeyCode::PageDown, '\u{e007}'),
+    (KeyCode::Delete, '\u{e008}'),
+];
+
+/// The char a key is stored as in a macro, if it can be stored.
+pub fn encode(key: &KeyEvent) -> Option<char> {
+    match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => c
+            .is_ascii_alphabetic()
+            .then(|| (c.to_ascii_lowercase() as u8 & 0x1f) as char),
+        KeyCode::Char(c) => Some(c),
+        KeyCode::Esc => Some('\x1b'),
+        KeyCode::Enter => Some('\r'),
+        KeyCode::Backspace => Some('\x7f'),
+        KeyCode::Tab => Some('\t'),
+        code => SPECIAL_KEYS
+            .iter()
+            .find(|(k, _)| *k == code)
+            .map(|&(_, c)| c),
+    }
+}
+
+/// The key a macro char stands for. Linewise registers end lines with `\n`,
+/// which plays back as Enter.
+pub fn decode(c: char) -> KeyEvent {
+    let (code, modifiers) = match c {
+        '\x1b' => (KeyCode::Esc, KeyModifiers::NONE),
+        '\r' | '\n' => (KeyCode::Enter, KeyModifiers::NONE),
+        '\x7f' => (KeyCode::Backspace, KeyModifiers::NONE),
+        '\t' => (KeyCode::Tab, KeyModifiers::NONE),
+        '\x01'..='\x1a' => (
+            KeyCode::Char((c as u8 - 1 + b'a') as char),
+            KeyModifiers::CONTROL,
+        ),
+        _ => match SPECIAL_KEYS.iter().find(|&&(_, s)| s == c) {
+            Some(&(code, _)) => (code, KeyModifiers::NONE),
+            None => (KeyCode::Char(c), KeyModifiers::NONE),
+        },
+    };
+    KeyEvent::new(code, modifiers)
+}
+
+/// Next input event: a key of the running macro, else one typed ahead while
+/// it ran, else whatever the terminal sends within `timeout`.
+pub fn next_event(ed: &mut Editor, timeout: Duration) -> io::Result<Option<Event>> {
+    if ed.macro_running() {
+        // Ctrl-C stops a macro that never fails; other keys wait their turn
+        while poll(Duration::ZERO)? {
+            if let Event::Key(key) = read()? {
+                if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                    ed.abort_macro();
+                    ed.set_status("Interrupted");
+                } else if let Some(c) = encode(&key) {
+                    ed.typeahead.push_back(c);
+                }
+            }
+        }
+    }
+    if let Some(c) = ed.next_macro_key() {
+        return Ok(Some(Event::Key(decode(c))));
+    }
+    let event = match ed.typeahead.pop_front() {
+        Some(c) => Event::Key(decode(c)),
+        None if poll(timeout)? => read()?,
+        None => return Ok(None),
+    };
+    if let Event::Key(key) = &event {
+        if let Some(c) = encode(key) {
+            ed.record_key(c);
+        }
+    }
+    Ok(Some(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_round_trip_through_macro_text() {
+        let keys = [
+            KeyEvent::new(KeyCode::Char('x'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('é'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
+            KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Delete, KeyModifiers::NONE),
+        ];
+        let text: String = keys.iter().filter_map(encode).collect();
+        assert!(text.starts_with("x\u{e9}\x12\x1b\r\x7f"));
+        let back: Vec<KeyEvent> = text.chars().map(decode).collect();
+        assert_eq!(back, keys);
+        assert_eq!(
+            decode('\n'),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)
+        );
+        // Keys without a stored form are left out of recordings
+        assert_eq!(
+            encode(&KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE)),
+            None
+        );
+    }
+}
@@ -6,6 +6,8 @@ use std::path::PathBuf;
 pub enum Mode {
     Normal,
     Insert,
+    /// `R`: typed text overwrites the line.
+    Replace,
     Visual,
     VisualLine,
     VisualBlock,
@@ -30,15 +32,61 @@ pub enum Action {
     OperatorDelete,
     OperatorChange,
     OperatorYank,
+    OperatorToggleCase,
+    OperatorLowercase,
+    OperatorUppercase,
+    OperatorShiftRight,
+    OperatorShiftLeft,
+    EnterReplace,
+    ToggleCase,
+    JoinLines,
+    JoinLinesRaw,
+    Increment,
+    Decrement,
     MoveWordForward,
     MoveWordBackward,
     MoveEndWord,
+    MoveEndWordBackward,
+    MoveBigWordForward,
+    MoveBigWordBackward,
+    MoveBigEndWord,
+    SentenceForward,
+    SentenceBackward,
+    ParagraphForward,
+    ParagraphBackward,
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
+    FirstNonBlank,
+    LineFirstNonBlank,
+    LastNonBlank,
+    MatchPair,
+    ScrollLineDown,
+    ScrollLineUp,
+    ScrollHalfDown,
+    ScrollHalfUp,
+    ScrollPageDown,
+    ScrollPageUp,
+    ScrollCenter,
+    ScrollTop,
+    ScrollBottom,
+    JumpOlder,
+    JumpNewer,
     Undo,
     Redo,
+    UndoEarlier,
+    UndoLater,
     CommandPrompt,
+    SearchForward,
+    SearchBackward,
+    SearchNext,
+    SearchPrev,
     EnterVisual,
     EnterVisualLine,
     EnterVisualBlock,
+    ReselectVisual,
+    NextTab,
+    PrevTab,
     PasteAfter,
     PasteBefore,
 }
@@ -57,6 +105,9 @@ pub fn default_keymap() -> HashMap<String, Action> {
     m.insert("i".into(), EnterInsert);
     m.insert("v".into(), EnterVisual);
     m.insert("V".into(), EnterVisualLine);
+    m.insert("gv".into(), ReselectVisual);
+    m.insert("gt".into(), NextTab);
+    m.insert("gT".into(), PrevTab);
     m.insert("a".into(), Append);
     m.insert("o".into(), OpenBelow);
     m.insert("O".into(), OpenAbove);
@@ -65,11 +116,57 @@ pub fn default_keymap() -> HashMap<String, Action> {
     m.insert("d".into(), OperatorDelete);
     m.insert("c".into(), OperatorChange);
     m.insert("y".into(), OperatorYank);
+    m.insert("g~".into(), OperatorToggleCase);
+    m.insert("gu".into(), OperatorLowercase);
+    m.insert("gU".into(), OperatorUppercase);
+    m.insert(">".into(), OperatorShiftRight);
+    m.insert("<".into(), OperatorShiftLeft);
+    m.insert("R".into(), EnterReplace);
+    m.insert("~".into(), ToggleCase);
+    m.insert("J".into(), JoinLines);
+    m.insert("gJ".into(), JoinLinesRaw);
+    // Ctrl-a, Ctrl-x
+    m.insert("\x01".into(), Increment);
+    m.insert("\x18".into(), Decrement);
     m.insert("u".into(), Undo);
+    m.insert("g-".into(), UndoEarlier);
+    m.insert("g+".into(), UndoLater);
     m.insert("w".into(), MoveWordForward);
     m.insert("b".into(), MoveWordBackward);
     m.insert("e".into(), MoveEndWord);
+    m.insert("ge".into(), MoveEndWordBackward);
+    m.insert("W".into(), MoveBigWordForward);
+    m.insert("B".into(), MoveBigWordBackward);
+    m.insert("E".into(), MoveBigEndWord);
+    m.insert(")".into(), SentenceForward);
+    m.insert("(".into(), SentenceBackward);
+    m.insert("}".into(), ParagraphForward);
+    m.insert("{".into(), ParagraphBackward);
+    m.insert("H".into(), ScreenTop);
+    m.insert("M".into(), ScreenMiddle);
+    m.insert("L".into(), ScreenBottom);
+    m.insert("^".into(), FirstNonBlank);
+    m.insert("_".into(), LineFirstNonBlank);
+    m.insert("g_".into(), LastNonBlank);
+    m.insert("%".into(), MatchPair);
+    // Ctrl-e, Ctrl-y, Ctrl-d, Ctrl-u, Ctrl-f, Ctrl-b
+    m.insert("\x05".into(), ScrollLineDown);
+    m.insert("\x19".into(), ScrollLineUp);
+    m.insert("\x04".into(), ScrollHalfDown);
+    m.insert("\x15".into(), ScrollHalfUp);
+    m.insert("\x06".into(), ScrollPageDown);
+    m.insert("\x02".into(), ScrollPageUp);
+    m.insert("zz".into(), ScrollCenter);
+    m.insert("zt".into(), ScrollTop);
+    m.insert("zb".into(), ScrollBottom);
+    // Ctrl-o, and Ctrl-i which terminals send as Tab
+    m.insert("\x0f".into(), JumpOlder);
+    m.insert("\t".into(), JumpNewer);
     m.insert(":".into(), CommandPrompt);
+    m.insert("/".into(), SearchForward);
+    m.insert("?".into(), SearchBackward);
+    m.insert("n".into(), SearchNext);
+    m.insert("N".into(), SearchPrev);
     m.insert("p".into(), PasteAfter);
     m.insert("P".into(), PasteBefore);
     m
@@ -95,23 +192,99 @@ fn parse_action(name: &str) -> Option<Action> {
         "delete" | "d" => Some(OperatorDelete),
         "change" | "c" => Some(OperatorChange),
         "yank" | "y" => Some(OperatorYank),
+        "toggle_case_op" | "g~" => Some(OperatorToggleCase),
+        "lowercase" | "gu" => Some(OperatorLowercase),
+        "uppercase" | "gU" => Some(OperatorUppercase),
+        "shift_right" | ">" => Some(OperatorShiftRight),
+        "shift_left" | "<" => Some(OperatorShiftLeft),
+        "replace_mode" | "R" => Some(EnterReplace),
+        "toggle_case" | "~" => Some(ToggleCase),
+        "join_lines" | "J" => Some(JoinLines),
+        "join_lines_raw" | "gJ" => Some(JoinLinesRaw),
+        "increment" => Some(Increment),
+        "decrement" => Some(Decrement),
         "undo" | "u" => Some(Undo),
         "redo" => Some(Redo),
+        "undo_earlier" | "g-" => Some(UndoEarlier),
+        "undo_later" | "g+" => Some(UndoLater),
         "move_word_forward" | "w" => Some(MoveWordForward),
         "move_word_backward" | "b" => Some(MoveWordBackward),
         "move_end_word" | "e" => Some(MoveEndWord),
+        "move_end_word_backward" | "ge" => Some(MoveEndWordBackward),
+        "move_big_word_forward" | "W" => Some(MoveBigWordForward),
+        "move_big_word_backward" | "B" => Some(MoveBigWordBackward),
+        "move_big_end_word" | "E" => Some(MoveBigEndWord),
+        "sentence_forward" | ")" => Some(SentenceForward),
+        "sentence_backward" | "(" => Some(SentenceBackward),
+        "paragraph_forward" | "}" => Some(ParagraphForward),
+        "paragraph_backward" | "{" => Some(ParagraphBackward),
+        "screen_top" | "H" => Some(ScreenTop),
+        "screen_middle" | "M" => Some(ScreenMiddle),
+        "screen_bottom" | "L" => Some(ScreenBottom),
+        "first_non_blank" | "^" => Some(FirstNonBlank),
+        "line_first_non_blank" | "_" => Some(LineFirstNonBlank),
+        "last_non_blank" | "g_" => Some(LastNonBlank),
+        "match_pair" | "%" => Some(MatchPair),
+        "scroll_line_down" => Some(ScrollLineDown),
+        "scroll_line_up" => Some(ScrollLineUp),
+        "scroll_half_down" => Some(ScrollHalfDown),
+        "scroll_half_up" => Some(ScrollHalfUp),
+        "scroll_page_down" => Some(ScrollPageDown),
+        "scroll_page_up" => Some(ScrollPageUp),
+        "scroll_center" | "zz" => Some(ScrollCenter),
+        "scroll_top" | "zt" => Some(ScrollTop),
+        "scroll_bottom" | "zb" => Some(ScrollBottom),
+        "jump_older" => Some(JumpOlder),
+        "jump_newer" => Some(JumpNewer),
         "command" | ":" => Some(CommandPrompt),
+        "search_forward" | "/" => Some(SearchForward),
+        "search_backward" | "?" => Some(SearchBackward),
+        "search_next" | "n" => Some(SearchNext),
+        "search_prev" | "N" => Some(SearchPrev),
         "visual" | "v" => Some(EnterVisual),
         "visual_line" | "V" => Some(EnterVisualLine),
+        "reselect_visual" | "gv" => Some(ReselectVisual),
+        "next_tab" | "gt" => Some(NextTab),
+        "prev_tab" | "gT" => Some(PrevTab),
         "paste_after" | "p" => Some(PasteAfter),
         "paste_before" | "P" => Some(PasteBefore),
         _ => None,
     }
 }
 
+/// Directory for per-user state such as undo files. Resolved like the config
+/// paths: `$XDG_STATE_HOME/rune`, then `~/.local/state/rune`, then
+/// `%LOCALAPPDATA%/rune` (Windows).
+pub fn state_dir() -> Option<PathBuf> {
+    if let Ok(xdg) = std::env::var("XDG_STATE_HOME") {
+        let mut p = PathBuf::from(xdg);
+        p.push("rune");
+        return Some(p);
+    }
+    if let Ok(home) = std::env::var("HOME") {
+        let mut p = PathBuf::from(home);
+        p.push(".local/state/rune");
+        return Some(p);
+    }
+    if let Ok(local) = std::env::var("LOCALAPPDATA") {
+        let mut p = PathBuf::from(local);
+        p.push("rune");
+        return Some(p);
+    }
+    None
+}
+
 pub struct EditorConfig {
     pub keymap: HashMap<String, Action>,
     pub start_in_insert: bool,
+    pub undolevels: Option<usize>,
+    pub undofile: bool,
+    pub scrolloff: usize,
+    pub shiftwidth: usize,
+    pub expandtab: bool,
+    pub clipboard_copy: Option<String>,
+    pub clipboard_paste: Option<String>,
+    pub osc52: bool,
 }
 
 pub fn load_config(mut base: HashMap<String, Action>) -> EditorConfig {
@@ -161,6 +334,14 @@ pub fn load_config(mut base: HashMap<String, Action>) -> EditorConfig {
         candidates.push(p2);
     }
     let mut start_in_insert = false;
+    let mut undolevels = None;
+    let mut undofile = false;
+    let mut scrolloff = 0;
+    let mut shiftwidth = 0;
+    let mut expandtab = false;
+    let mut clipboard_copy = None;
+    let mut clipboard_paste = None;
+    let mut osc52 = true;
     for path in candidates {
         if let Ok(content) = fs::read_to_string(&path) {
             let mut section = String::new();
@@ -184,11 +365,36 @@ pub fn load_config(mut base: HashMap<String, Action>) -> EditorConfig {
                                 base.insert(seq.to_string(), act);
                             }
                         }
-                        "general" => {
-                            if key.eq_ignore_ascii_case("start_in_insert") {
-                                let v = val.trim_matches('"').to_ascii_lowercase();
-                                start_in_insert = matches!(v.as_str(), "1" | "true" | "yes" | "on");
-                            }
+                        "general" if key.eq_ignore_ascii_case("start_in_insert") => {
+                            let v = val.trim_matches('"').to_ascii_lowercase();
+                            start_in_insert = matches!(v.as_str(), "1" | "true" | "yes" | "on");
+                        }
+                        "general" if key.eq_ignore_ascii_case("undofile") => {
+                            let v = val.trim_matches('"').to_ascii_lowercase();
+                            undofile = matches!(v.as_str(), "1" | "true" | "yes" | "on");
+                        }
+                        "general" if key.eq_ignore_ascii_case("osc52") => {
+                            let v = val.trim_matches('"').to_ascii_lowercase();
+                            osc52 = matches!(v.as_str(), "1" | "true" | "yes" | "on");
+                        }
+                        "clipboard" if key.eq_ignore_ascii_case("copy") => {
+                            clipboard_copy = Some(val.trim_matches('"').to_string());
+                        }
+                        "clipboard" if key.eq_ignore_ascii_case("paste") => {
+                            clipboard_paste = Some(val.trim_matches('"').to_string());
+                        }
+                        "general" if key.eq_ignore_ascii_case("shiftwidth") => {
+                            shiftwidth = val.trim_matches('"').parse().unwrap_or(shiftwidth);
+                        }
+                        "general" if key.eq_ignore_ascii_case("expandtab") => {
+                            let v = val.trim_matches('"').to_ascii_lowercase();
+                            expandtab = matches!(v.as_str(), "1" | "true" | "yes" | "on");
+                        }
+                        "general" if key.eq_ignore_ascii_case("scrolloff") => {
+                            scrolloff = val.trim_matches('"').parse().unwrap_or(scrolloff);
+                        }
+                        "general" if key.eq_ignore_ascii_case("undolevels") => {
+                            undolevels = val.trim_matches('"').parse().ok().or(undolevels);
                         }
                         _ => {}
                     }
@@ -199,5 +405,13 @@ pub fn load_config(mut base: HashMap<String, Action>) -> EditorConfig {
     EditorConfig {
         keymap: base,
         start_in_insert,
+        undolevels,
+        undofile,
+        scrolloff,
+        shiftwidth,
+        expandtab,
+        clipboard_copy,
+        clipboard_paste,
+        osc52,
     }
 }
@@ -1,8 +1,19 @@
 mod app;
 mod buffer;
+mod buflist;
+mod clipboard;
 mod editor;
+mod encoding;
+mod ex;
+mod input;
 mod keymap;
+mod number;
+mod regex;
+mod registers;
+mod textobject;
 mod ui;
+mod undo;
+mod window;
 
 fn main() -> std::io::Result<()> {
     app::run()
@@ -0,0 +1,165 @@
+//! `Ctrl-a`/`Ctrl-x`: finding the number under or after the cursor and
+//! adding to it. Decimal numbers may be negative; `0x` hex, `0b` binary and
+//! octal with a leading `0` wrap around as unsigned 64-bit values. Leading
+//! zeros keep a number's width.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Radix {
+    Decimal,
+    Hex,
+    Octal,
+    Binary,
+}
+
+/// A number found on a line: chars `start..end`, including a `-` sign or a
+/// `0x`/`0b` prefix.
+struct Number {
+    start: usize,
+    end: usize,
+    radix: Radix,
+}
+
+/// Adds `delta` to the first number on `line` that ends after char `cursor`.
+/// Returns its char range and the text that replaces it.
+pub fn increment(line: &str, cursor: usize, delta: i64) -> Option<(usize, usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let num = find(&chars, cursor)?;
+    let text: String = chars[num.start..num.end].iter().collect();
+    let new = match num.radix {
+        Radix::Decimal => {
+            // Out of range values stick at the limits
+            let value = text.parse::<i64>().unwrap_or(if text.starts_with('-') {
+                i64::MIN
+            } else {
+                i64::MAX
+            });
+            let value = value.saturating_add(delta);
+            // Leading zeros keep the width, as in `007` -> `010`
+            let width = text.trim_start_matches('-').len();
+            let sign = if value < 0 { "-" } else { "" };
+            if text.trim_start_matches('-').starts_with('0') {
+                format!("{}{:0width$}", sign, value.unsigned_abs())
+            } else {
+                value.to_string()
+            }
+        }
+        radix => {
+            let (prefix, digits) = match radix {
+                Radix::Octal => ("0", &text[1..]),
+                _ => text.split_at(2),
+            };
+            let base = match radix {
+                Radix::Hex => 16,
+                Radix::Binary => 2,
+                _ => 8,
+            };
+            let value = u64::from_str_radix(digits, base).unwrap_or(u64::MAX);
+            let value = value.wrapping_add(delta as u64);
+            let width = digits.len();
+            let digits = match radix {
+                Radix::Hex
+                    if digits
+                        .chars()
+                        .rev()
+                        .find(char::is_ascii_alphabetic)
+                        .is_some_and(|c| c.is_ascii_uppercase()) =>
+                {
+                    format!("{:0width$X}", value)
+                }
+                Radix::Hex => format!("{:0width$x}", value),
+                Radix::Binary => format!("{:0width$b}", value),
+                _ => format!("{:0width$o}", value),
+            };
+            format!("{}{}", prefix, digits)
+        }
+    };
+    Some((num.start, num.end, new))
+}
+
+/// The first number that ends after `cursor`.
+fn find(chars: &[char], cursor: usize) -> Option<Number> {
+    let mut i = 0;
+    while i < chars.len() {
+        let Some(mut num) = number_at(chars, i) else {
+            i += 1;
+            continue;
+        };
+        if num.end > cursor {
+            if num.radix == Radix::Decimal && num.start > 0 && chars[num.start - 1] == '-' {
+                num.start -= 1;
+            }
+            return Some(num);
+        }
+        i = num.end;
+    }
+    None
+}
+
+/// The number starting at `i`, if a digit is there.
+fn number_at(chars: &[char], i: usize) -> Option<Number> {
+    if !chars[i].is_ascii_digit() {
+        return None;
+    }
+    let digits_from = |from: usize, ok: fn(&char) -> bool| {
+        from + chars[from..].iter().take_while(|c| ok(c)).count()
+    };
+    let next = chars.get(i + 1).copied();
+    let after = chars.get(i + 2);
+    if chars[i] == '0' {
+        if matches!(next, Some('x' | 'X')) && after.is_some_and(char::is_ascii_hexdigit) {
+            let end = digits_from(i + 2, char::is_ascii_hexdigit);
+            return Some(Number {
+                start: i,
+                end,
+                radix: Radix::Hex,
+            });
+        }
+        if matches!(next, Some('b' | 'B')) && after.is_some_and(|c| matches!(c, '0' | '1')) {
+            let end = digits_from(i + 2, |c| matches!(c, '0' | '1'));
+            return Some(Number {
+                start: i,
+                end,
+                radix: Radix::Binary,
+            });
+        }
+    }
+    let end = digits_from(i, char::is_ascii_digit);
+    let octal = chars[i] == '0' && end - i > 1 && chars[i..end].iter().all(|c| *c < '8');
+    Some(Number {
+        start: i,
+        end,
+        radix: if octal { Radix::Octal } else { Radix::Decimal },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inc(line: &str, cursor: usize, delta: i64) -> Option<String> {
+        increment(line, cursor, delta).map(|(s, e, new)| {
+            let chars: Vec<char> = line.chars().collect();
+            let before: String = chars[..s].iter().collect();
+            let after: String = chars[e..].iter().collect();
+            before + &new + &after
+        })
+    }
+
+    #[test]
+    fn numbers_in_each_radix() {
+        assert_eq!(inc("x = 9;", 0, 1).as_deref(), Some("x = 10;"));
+        assert_eq!(inc("x = -1;", 0, 3).as_deref(), Some("x = 2;"));
+        assert_eq!(inc("x = 1;", 4, -3).as_deref(), Some("x = -2;"));
+        // Under the cursor rather than the first on the line
+        assert_eq!(inc("a1 b22", 4, 1).as_deref(), Some("a1 b23"));
+        assert_eq!(inc("0x0fF", 0, 1).as_deref(), Some("0x100"));
+        assert_eq!(inc("0xfe", 3, 1).as_deref(), Some("0xff"));
+        assert_eq!(inc("0xaB", 0, 1).as_deref(), Some("0xAC"));
+        assert_eq!(inc("0x00", 0, -1).as_deref(), Some("0xffffffffffffffff"));
+        assert_eq!(inc("0b0111", 0, 1).as_deref(), Some("0b1000"));
+        assert_eq!(inc("007", 0, 1).as_deref(), Some("010"));
+        assert_eq!(inc("009", 0, 1).as_deref(), Some("010"));
+        assert_eq!(inc("no digits", 0, 1), None);
+        assert_eq!(inc("1 and", 2, 1), None);
+    }
+}
@@ -0,0 +1,930 @@
+//! Vim-flavoured regular expressions, matched with a Pike VM over a stream of
+//! chars so a pattern can run straight off rope chunks.
+//!
+//! Supported syntax (magic mode, Vim's default):
+//! - `.` any char but newline, `\_.` any char, `\n` newline, `\t` tab
+//! - `*`, `\+`, `\?`/`\=`, `\{n,m}` (`\{-n,m}` is non-greedy)
+//! - `[abc]`, `[^a-z]`, and the classes `\s \d \w \a \l \u \x` (uppercase negates)
+//! - `\(...\)` groups, `\%(...\)` non-capturing groups, `\|` alternation
+//! - `^`/`$` line anchors, `\<`/`\>` word boundaries, `\zs`/`\ze` match bounds
+//! - `\c`/`\C` force ignoring/matching case, `\v` very magic, `\m` magic
+
+/// Error from compiling a pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError(pub String);
+
+impl std::fmt::Display for RegexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn err<T>(msg: &str) -> Result<T, RegexError> {
+    Err(RegexError(msg.to_string()))
+}
+
+/// Upper bound for `\{n,m}` counts, which are expanded into the program.
+const MAX_REPEAT: usize = 1000;
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// True if `pat` has an uppercase letter outside of escapes (for `smartcase`).
+pub fn has_uppercase(pat: &str) -> bool {
+    let mut chars = pat.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Named {
+    Space,
+    Digit,
+    Word,
+    Alpha,
+    Lower,
+    Upper,
+    Hex,
+}
+
+impl Named {
+    fn from_escape(c: char) -> Option<(Named, bool)> {
+        let named = match c.to_ascii_lowercase() {
+            's' => Named::Space,
+            'd' => Named::Digit,
+            'w' => Named::Word,
+            'a' => Named::Alpha,
+            'l' => Named::Lower,
+            'u' => Named::Upper,
+            'x' => Named::Hex,
+            _ => return None,
+        };
+        Some((named, c.is_ascii_uppercase()))
+    }
+
+    fn matches(self, c: char) -> bool {
+        match self {
+            Named::Space => c == ' ' || c == '\t',
+            Named::Digit => c.is_ascii_digit(),
+            Named::Word => is_word_char(c),
+            Named::Alpha => c.is_alphabetic(),
+            Named::Lower => c.is_lowercase(),
+            Named::Upper => c.is_uppercase(),
+            Named::Hex => c.is_ascii_hexdigit(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClassItem {
+    Range(char, char),
+    Named(Named, bool),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Class {
+    items: Vec<ClassItem>,
+    negated: bool,
+}
+
+impl Class {
+    fn matches(&self, c: char, icase: bool) -> bool {
+        // Like Vim, no class (not even a negated one) matches a line break
+        if c == '\n' {
+            return false;
+        }
+        let hit = |c: char| {
+            self.items.iter().any(|it| match *it {
+                ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+                ClassItem::Named(n, neg) => n.matches(c) != neg,
+            })
+        };
+        let found = hit(c) || (icase && (c.to_lowercase().any(hit) || c.to_uppercase().any(hit)));
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Look {
+    LineStart,
+    LineEnd,
+    WordStart,
+    WordEnd,
+}
+
+impl Look {
+    fn holds(self, prev: Option<char>, next: Option<char>) -> bool {
+        let word = |c: Option<char>| c.is_some_and(is_word_char);
+        match self {
+            Look::LineStart => prev.is_none_or(|c| c == '\n'),
+            Look::LineEnd => next.is_none_or(|c| c == '\n'),
+            Look::WordStart => !word(prev) && word(next),
+            Look::WordEnd => word(prev) && !word(next),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    AnyNewline,
+    Class(Class),
+    Look(Look),
+    Star,
+    Plus,
+    Question,
+    Brace {
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+    Open {
+        capture: bool,
+    },
+    Close,
+    Alt,
+    MatchStart,
+    MatchEnd,
+}
+
+#[derive(Debug, Default)]
+struct Lexed {
+    tokens: Vec<Token>,
+    /// `Some(true)` for `\c`, `Some(false)` for `\C`.
+    ignore_case: Option<bool>,
+}
+
+fn lex(pat: &str) -> Result<Lexed, RegexError> {
+    let chars: Vec<char> = pat.chars().collect();
+    let mut out = Lexed::default();
+    let mut very_magic = false;
+    let mut i = 0;
+    // `^` is an anchor only where a branch starts
+    let at_branch_start = |toks: &[Token]| {
+        matches!(
+            toks.last(),
+            None | Some(Token::Alt) | Some(Token::Open { .. })
+        )
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        let tok = if c == '\\' {
+            let Some(&e) = chars.get(i) else {
+                return err("trailing backslash");
+            };
+            i += 1;
+            match e {
+                'v' => {
+                    very_magic = true;
+                    continue;
+                }
+                'm' => {
+                    very_magic = false;
+                    continue;
+                }
+                'c' => {
+                    out.ignore_case = Some(true);
+                    continue;
+                }
+                'C' => {
+                    out.ignore_case.get_or_insert(false);
+                    continue;
+                }
+                'n' => Token::Char('\n'),
+                't' => Token::Char('\t'),
+                'e' => Token::Char('\x1b'),
+                'r' => Token::Char('\r'),
+                'z' => {
+                    let t = match chars.get(i) {
+                        Some('s') => Token::MatchStart,
+                        Some('e') => Token::MatchEnd,
+                        _ => return err("unknown \\z item"),
+                    };
+                    i += 1;
+                    t
+                }
+                '_' => match chars.get(i) {
+                    Some('.') => {
+                        i += 1;
+                        Token::AnyNewline
+                    }
+                    _ => return err("unsupported \\_ item"),
+                },
+                _ if Named::from_escape(e).is_some() => {
+                    let (n, neg) = Named::from_escape(e).unwrap_or((Named::Word, false));
+                    Token::Class(Class {
+                        items: vec![ClassItem::Named(n, false)],
+                        negated: neg,
+                    })
+                }
+                '(' | ')' | '|' | '+' | '?' | '=' | '{' | '<' | '>' if !very_magic => {
+                    special(e, &chars, &mut i)?
+                }
+                '%' if !very_magic && chars.get(i) == Some(&'(') => {
+                    i += 1;
+                    Token::Open { capture: false }
+                }
+                _ => Token::Char(e),
+            }
+        } else {
+            match c {
+                '.' => Token::Any,
+                '*' => Token::Star,
+                '[' => match lex_class(&chars, i)? {
+                    Some((class, next)) => {
+                        i = next;
+                        Token::Class(class)
+                    }
+                    None => Token::Char('['),
+                },
+                '^' if at_branch_start(&out.tokens) => Token::Look(Look::LineStart),
+                '$' if at_branch_end(&chars, i, very_magic) => Token::Look(Look::LineEnd),
+                '(' | ')' | '|' | '+' | '?' | '=' | '{' | '<' | '>' if very_magic => {
+                    special(c, &chars, &mut i)?
+                }
+                '%' if very_magic && chars.get(i) == Some(&'(') => {
+                    i += 1;
+                    Token::Open { capture: false }
+                }
+                _ => Token::Char(c),
+            }
+        };
+        out.tokens.push(tok);
+    }
+    Ok(out)
+}
+
+/// `$` is an anchor only where a branch ends.
+fn at_branch_end(chars: &[char], i: usize, very_magic: bool) -> bool {
+    match chars.get(i) {
+        None => true,
+        Some('|') | Some(')') if very_magic => true,
+        Some('\\') => match chars.get(i + 1) {
+            Some('|') | Some(')') => !very_magic,
+            Some('n') => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Operators spelled `\x` in magic mode and `x` in very magic mode.
+fn special(c: char, chars: &[char], i: &mut usize) -> Result<Token, RegexError> {
+    Ok(match c {
+        '(' => Token::Open { capture: true },
+        ')' => Token::Close,
+        '|' => Token::Alt,
+        '+' => Token::Plus,
+        '?' | '=' => Token::Question,
+        '<' => Token::Look(Look::WordStart),
+        '>' => Token::Look(Look::WordEnd),
+        _ => lex_brace(chars, i)?,
+    })
+}
+
+/// Parses the body of `\{n,m}` after the opening brace.
+fn lex_brace(chars: &[char], i: &mut usize) -> Result<Token, RegexError> {
+    let mut body = String::new();
+    loop {
+        match chars.get(*i) {
+            None => return err("missing } after \\{"),
+            Some('}') => {
+                *i += 1;
+                break;
+            }
+            Some('\\') if chars.get(*i + 1) == Some(&'}') => {
+                *i += 2;
+                break;
+            }
+            Some(&c) => {
+                body.push(c);
+                *i += 1;
+            }
+        }
+    }
+    let (greedy, body) = match body.strip_prefix('-') {
+        Some(rest) => (false, rest),
+        None => (true, body.as_str()),
+    };
+    let num = |s: &str, default: Option<usize>| -> Result<Option<usize>, RegexError> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(default);
+        }
+        match s.parse::<usize>() {
+            Ok(n) if n <= MAX_REPEAT => Ok(Some(n)),
+            Ok(_) => err("count too large in \\{}"),
+            Err(_) => err("invalid count in \\{}"),
+        }
+    };
+    let (min, max) = match body.split_once(',') {
+        Some((lo, hi)) => (num(lo, Some(0))?.unwrap_or(0), num(hi, None)?),
+        None => {
+            let n = num(body, None)?;
+            (n.unwrap_or(0), n)
+        }
+    };
+    if max.is_some_and(|m| m < min) {
+        return err("invalid range in \\{}");
+    }
+    Ok(Token::Brace { min, max, greedy })
+}
+
+/// Parses a `[...]` class starting after the `[`. Returns `None` when there
+/// is no closing bracket, in which case `[` is literal (as in Vim).
+fn lex_class(chars: &[char], mut i: usize) -> Result<Option<(Class, usize)>, RegexError> {
+    let mut class = Class {
+        items: Vec::new(),
+        negated: false,
+    };
+    if chars.get(i) == Some(&'^') {
+        class.negated = true;
+        i += 1;
+    }
+    let mut first = true;
+    loop {
+        let Some(&c) = chars.get(i) else {
+            return Ok(None);
+        };
+        i += 1;
+        if c == ']' && !first {
+            return Ok(Some((class, i)));
+        }
+        first = false;
+        let lo = if c == '\\' {
+            let Some(&e) = chars.get(i) else {
+                return Ok(None);
+            };
+            i += 1;
+            match e {
+                'n' => '\n',
+                't' => '\t',
+                'e' => '\x1b',
+                'r' => '\r',
+                '\\' | ']' | '^' | '-' => e,
+                _ => match Named::from_escape(e) {
+                    Some((n, neg)) => {
+                        class.items.push(ClassItem::Named(n, neg));
+                        continue;
+                    }
+                    None => {
+                        // Unknown escapes keep the backslash, like Vim
+                        class.items.push(ClassItem::Range('\\', '\\'));
+                        e
+                    }
+                },
+            }
+        } else {
+            c
+        };
+        if chars.get(i) == Some(&'-') && chars.get(i + 1).is_some_and(|&h| h != ']') {
+            let hi = chars[i + 1];
+            i += 2;
+            if hi < lo {
+                return err("reverse range in [] class");
+            }
+            class.items.push(ClassItem::Range(lo, hi));
+        } else {
+            class.items.push(ClassItem::Range(lo, lo));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    AnyNewline,
+    Class(Class),
+    Look(Look),
+    MatchStart,
+    MatchEnd,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn alternation(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.concat()?];
+        while self.peek() == Some(&Token::Alt) {
+            self.pos += 1;
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap_or(Node::Empty)
+        } else {
+            Node::Alt(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, RegexError> {
+        let mut items = Vec::new();
+        while let Some(tok) = self.peek() {
+            if matches!(tok, Token::Alt | Token::Close) {
+                break;
+            }
+            items.push(self.repeat()?);
+        }
+        Ok(Node::Concat(items))
+    }
+
+    fn repeat(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.atom()?;
+        loop {
+            let (min, max, greedy) = match self.peek() {
+                Some(Token::Star) => (0, None, true),
+                Some(Token::Plus) => (1, None, true),
+                Some(Token::Question) => (0, Some(1), true),
+                Some(&Token::Brace { min, max, greedy }) => (min, max, greedy),
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, RegexError> {
+        let Some(tok) = self.tokens.get(self.pos).cloned() else {
+            return Ok(Node::Empty);
+        };
+        self.pos += 1;
+        Ok(match tok {
+            // A leading `*` is literal in Vim
+            Token::Star => Node::Char('*'),
+            Token::Char(c) => Node::Char(c),
+            Token::Any => Node::Any,
+            Token::AnyNewline => Node::AnyNewline,
+            Token::Class(c) => Node::Class(c),
+            Token::Look(l) => Node::Look(l),
+            Token::MatchStart => Node::MatchStart,
+            Token::MatchEnd => Node::MatchEnd,
+            Token::Open { capture } => {
+                let index = if capture {
+                    self.groups += 1;
+                    Some(self.groups)
+                } else {
+                    None
+                };
+                let inner = self.alternation()?;
+                if self.peek() != Some(&Token::Close) {
+                    return err("unmatched \\(");
+                }
+                self.pos += 1;
+                Node::Group(Box::new(inner), index)
+            }
+            Token::Close => return err("unmatched \\)"),
+            Token::Plus | Token::Question | Token::Brace { .. } => return err("nothing to repeat"),
+            Token::Alt => return err("unexpected \\|"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    AnyNewline,
+    Class(Class),
+    Look(Look),
+    /// Try the first target, then the second.
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+    zs_slot: usize,
+    ze_slot: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> usize {
+        self.prog.push(inst);
+        self.prog.len() - 1
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), RegexError> {
+        if self.prog.len() > 100_000 {
+            return err("pattern too large");
+        }
+        match node {
+            Node::Empty => {}
+            Node::Char(c) => {
+                self.emit(Inst::Char(*c));
+            }
+            Node::Any => {
+                self.emit(Inst::Any);
+            }
+            Node::AnyNewline => {
+                self.emit(Inst::AnyNewline);
+            }
+            Node::Class(c) => {
+                self.emit(Inst::Class(c.clone()));
+            }
+            Node::Look(l) => {
+                self.emit(Inst::Look(*l));
+            }
+            Node::MatchStart => {
+                self.emit(Inst::Save(self.zs_slot));
+            }
+            Node::MatchEnd => {
+                self.emit(Inst::Save(self.ze_slot));
+            }
+            Node::Group(inner, index) => {
+                if let Some(i) = index {
+                    self.emit(Inst::Save(2 * i));
+                }
+                self.compile(inner)?;
+                if let Some(i) = index {
+                    self.emit(Inst::Save(2 * i + 1));
+                }
+            }
+            Node::Concat(items) => {
+                for n in items {
+                    self.compile(n)?;
+                }
+            }
+            Node::Alt(branches) => {
+                let mut jumps = Vec::new();
+                for (k, b) in branches.iter().enumerate() {
+                    if k + 1 < branches.len() {
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.compile(b)?;
+                        jumps.push(self.emit(Inst::Jmp(0)));
+                        let next = self.prog.len();
+                        self.prog[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(b)?;
+                    }
+                }
+                let end = self.prog.len();
+                for j in jumps {
+                    self.prog[j] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.compile(node)?;
+                        self.emit(Inst::Jmp(split));
+                        let out = self.prog.len();
+                        self.prog[split] = self.split(split + 1, out, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.emit(Inst::Split(0, 0)));
+                            self.compile(node)?;
+                        }
+                        let out = self.prog.len();
+                        for s in splits {
+                            self.prog[s] = self.split(s + 1, out, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn split(&self, body: usize, out: usize, greedy: bool) -> Inst {
+        if greedy {
+            Inst::Split(body, out)
+        } else {
+            Inst::Split(out, body)
+        }
+    }
+}
+
+/// Capture positions of one match, as char offsets into the searched text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures {
+    slots: Vec<Option<usize>>,
+    groups: usize,
+}
+
+impl Captures {
+    /// Start of the match (moved by `\zs`).
+    pub fn start(&self) -> usize {
+        self.slots[2 * self.groups + 2]
+            .or(self.slots[0])
+            .unwrap_or(0)
+    }
+
+    /// End of the match, exclusive (moved by `\ze`).
+    pub fn end(&self) -> usize {
+        let end = self.slots[2 * self.groups + 3]
+            .or(self.slots[1])
+            .unwrap_or(0);
+        end.max(self.start())
+    }
+
+    /// Range of group `i` (`0` is the whole match), if it took part.
+    pub fn group(&self, i: usize) -> Option<(usize, usize)> {
+        if i == 0 {
+            return Some((self.start(), self.end()));
+        }
+        if i > self.groups {
+            return None;
+        }
+        Some((self.slots[2 * i]?, self.slots[2 * i + 1]?))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    groups: usize,
+    ignore_case: bool,
+}
+
+impl Regex {
+    /// Compiles `pat`. `ignore_case` is the default case mode; `\c` and `\C`
+    /// in the pattern override it.
+    pub fn new(pat: &str, ignore_case: bool) -> Result<Self, RegexError> {
+        let lexed = lex(pat)?;
+        let mut parser = Parser {
+            tokens: lexed.tokens,
+            pos: 0,
+            groups: 0,
+        };
+        let ast = parser.alternation()?;
+        if parser.pos < parser.tokens.len() {
+            return err("unmatched \\)");
+        }
+        let groups = parser.groups;
+        let mut c = Compiler {
+            prog: Vec::new(),
+            zs_slot: 2 * groups + 2,
+            ze_slot: 2 * groups + 3,
+        };
+        c.emit(Inst::Save(0));
+        c.compile(&ast)?;
+        c.emit(Inst::Save(1));
+        c.emit(Inst::Match);
+        Ok(Self {
+            prog: c.prog,
+            groups,
+            ignore_case: lexed.ignore_case.unwrap_or(ignore_case),
+        })
+    }
+
+    /// Finds the leftmost match in `chars`, whose first char sits at offset
+    /// `start`; `prev` is the char before it (for `^` and `\<`). Only matches
+    /// beginning at or before offset `until` are considered, so scanning stops
+    /// soon after `until` instead of running to the end of the text.
+    pub fn search<I: Iterator<Item = char>>(
+        &self,
+        mut chars: I,
+        start: usize,
+        prev: Option<char>,
+        until: usize,
+    ) -> Option<Captures> {
+        let nslots = 2 * self.groups + 4;
+        let mut clist = Threads::new(self.prog.len());
+        let mut nlist = Threads::new(self.prog.len());
+        let mut matched: Option<Vec<Option<usize>>> = None;
+        let mut pos = start;
+        let mut prev = prev;
+        let mut cur = chars.next();
+        let mut next = cur.and_then(|_| chars.next());
+        loop {
+            if matched.is_none() && pos <= until {
+                self.add_thread(&mut clist, 0, vec![None; nslots], pos, prev, cur);
+            }
+            if clist.list.is_empty() && (matched.is_some() || pos >= until) {
+                break;
+            }
+            for (pc, slots) in std::mem::take(&mut clist.list) {
+                let step = match (&self.prog[pc], cur) {
+                    (Inst::Match, _) => {
+                        matched = Some(slots);
+                        // Lower-priority threads can only give a worse match
+                        break;
+                    }
+                    (_, None) => false,
+                    (Inst::Char(want), Some(c)) => {
+                        c == *want || (self.ignore_case && fold(c) == fold(*want))
+                    }
+                    (Inst::Any, Some(c)) => c != '\n',
+                    (Inst::AnyNewline, Some(_)) => true,
+                    (Inst::Class(class), Some(c)) => class.matches(c, self.ignore_case),
+                    _ => false,
+                };
+                if step {
+                    self.add_thread(&mut nlist, pc + 1, slots, pos + 1, cur, next);
+                }
+            }
+            if cur.is_none() {
+                break;
+            }
+            std::mem::swap(&mut clist, &mut nlist);
+            nlist.clear();
+            pos += 1;
+            prev = cur;
+            cur = next;
+            next = cur.and_then(|_| chars.next());
+        }
+        matched.map(|slots| Captures {
+            slots,
+            groups: self.groups,
+        })
+    }
+
+    /// Follows jumps, splits, saves and assertions from `pc`, queueing every
+    /// thread that ends on a char-consuming instruction or `Match`.
+    fn add_thread(
+        &self,
+        list: &mut Threads,
+        pc: usize,
+        slots: Vec<Option<usize>>,
+        pos: usize,
+        prev: Option<char>,
+        cur: Option<char>,
+    ) {
+        let mut stack = vec![(pc, slots)];
+        while let Some((pc, mut slots)) = stack.pop() {
+            if !list.mark(pc) {
+                continue;
+            }
+            match &self.prog[pc] {
+                Inst::Jmp(t) => stack.push((*t, slots)),
+                Inst::Split(a, b) => {
+                    // Pushed in reverse so `a` is explored first
+                    stack.push((*b, slots.clone()));
+                    stack.push((*a, slots));
+                }
+                Inst::Save(i) => {
+                    slots[*i] = Some(pos);
+                    stack.push((pc + 1, slots));
+                }
+                Inst::Look(l) => {
+                    if l.holds(prev, cur) {
+                        stack.push((pc + 1, slots));
+                    }
+                }
+                _ => list.list.push((pc, slots)),
+            }
+        }
+    }
+}
+
+/// Thread list for one input position, deduplicated by program counter.
+struct Threads {
+    list: Vec<(usize, Vec<Option<usize>>)>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Self {
+            list: Vec::new(),
+            seen: vec![false; len],
+        }
+    }
+
+    /// Marks `pc` as visited; false if it already was.
+    fn mark(&mut self, pc: usize) -> bool {
+        !std::mem::replace(&mut self.seen[pc], true)
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.seen.fill(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pat: &str, text: &str) -> Option<(usize, usize)> {
+        let re = Regex::new(pat, false).unwrap();
+        re.search(text.chars(), 0, None, usize::MAX)
+            .map(|m| (m.start(), m.end()))
+    }
+
+    #[test]
+    fn literals_classes_and_repeats() {
+        assert_eq!(find("b.d", "abcd"), Some((1, 4)));
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+        assert_eq!(find("a\\+", "baaa"), Some((1, 4)));
+        assert_eq!(find("colou\\=r", "color"), Some((0, 5)));
+        assert_eq!(find("[0-9]\\{2,3}", "a12345"), Some((1, 4)));
+        assert_eq!(find("[0-9]\\{-2,3}", "a12345"), Some((1, 3)));
+        assert_eq!(find("[^a-c]", "abcd"), Some((3, 4)));
+        assert_eq!(find("\\d\\+", "ab 42"), Some((3, 5)));
+        assert_eq!(find("a[", "xa["), Some((1, 3)));
+        assert_eq!(find("*a", "x*a"), Some((1, 3)));
+    }
+
+    #[test]
+    fn alternation_prefers_leftmost_then_first_branch() {
+        assert_eq!(find("bc\\|abc", "xabc"), Some((1, 4)));
+        assert_eq!(find("a\\|ab", "ab"), Some((0, 1)));
+        assert_eq!(find("\\v(ab|cd)+", "xabcdab"), Some((1, 7)));
+    }
+
+    #[test]
+    fn anchors_and_word_boundaries() {
+        assert_eq!(find("^b", "ab\nb"), Some((3, 4)));
+        assert_eq!(find("a$", "ab\na"), Some((3, 4)));
+        assert_eq!(find("\\<in\\>", "inside in"), Some((7, 9)));
+        assert_eq!(find("\\vin>", "inside tin"), Some((8, 10)));
+        assert_eq!(find("a^", "a^"), Some((0, 2)));
+    }
+
+    #[test]
+    fn newlines_only_match_explicitly() {
+        assert_eq!(find("b.c", "ab\nc"), None);
+        assert_eq!(find("b\\nc", "ab\nc"), Some((1, 4)));
+        assert_eq!(find("b\\_.c", "ab\nc"), Some((1, 4)));
+        assert_eq!(find("[^x]c", "\nc"), None);
+    }
+
+    #[test]
+    fn case_flags() {
+        let re = Regex::new("abc", true).unwrap();
+        assert!(re.search("xABc".chars(), 0, None, usize::MAX).is_some());
+        assert_eq!(find("\\cabc", "ABC"), Some((0, 3)));
+        let re = Regex::new("\\Cabc", true).unwrap();
+        assert!(re.search("ABC".chars(), 0, None, usize::MAX).is_none());
+        assert!(has_uppercase("Foo"));
+        assert!(!has_uppercase("\\Sfoo"));
+    }
+
+    #[test]
+    fn groups_and_match_bounds() {
+        let re = Regex::new("\\(\\w\\+\\)=\\(\\d*\\)", false).unwrap();
+        let m = re.search("set x=42;".chars(), 0, None, usize::MAX).unwrap();
+        assert_eq!(m.group(1), Some((4, 5)));
+        assert_eq!(m.group(2), Some((6, 8)));
+        assert_eq!(find("foo\\zsbar", "foobar"), Some((3, 6)));
+        assert_eq!(find("foo\\zebar", "foobar foo"), Some((0, 3)));
+    }
+
+    #[test]
+    fn until_limits_match_starts() {
+        let re = Regex::new("z", false).unwrap();
+        assert!(re.search("abcz".chars(), 0, None, 2).is_none());
+        assert_eq!(
+            re.search("abcz".chars(), 10, None, 13).map(|m| m.start()),
+            Some(13)
+        );
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        assert!(Regex::new("\\(a", false).is_err());
+        assert!(Regex::new("a\\)", false).is_err());
+        assert!(Regex::new("\\+", false).is_err());
+        assert!(Regex::new("a\\{3,1}", false).is_err());
+    }
+}
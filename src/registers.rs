@@ -0,0 +1,246 @@
+//! Vim-style registers: where yanked and deleted text goes and what `p` puts.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+/// Text held by a register. Linewise text has no trailing newline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    pub kind: ClipboardKind,
+}
+
+impl Register {
+    pub fn new(text: impl Into<String>, kind: ClipboardKind) -> Self {
+        Self {
+            text: text.into(),
+            kind,
+        }
+    }
+}
+
+/// The registers stored by the editor. `"%` and `"/` are derived from the
+/// editor state instead (see `Editor::register`).
+#[derive(Default)]
+pub struct Registers {
+    /// `""`: a copy of whichever register was written last.
+    unnamed: Option<Register>,
+    /// `a`-`z`, `0`-`9`, `-`, the read-only `.` and `:`, and the last text
+    /// copied to the system clipboard as `+` and `*`.
+    stored: HashMap<char, Register>,
+}
+
+/// Order of `:registers`, as in Vim.
+const LIST_ORDER: &str = "\"0123456789abcdefghijklmnopqrstuvwxyz-*+.:%/";
+
+/// Whether `name` can follow `"` in Normal mode.
+pub fn is_valid(name: char) -> bool {
+    is_writable(name) || matches!(name, '.' | '%' | ':' | '/')
+}
+
+/// Whether text can be yanked or deleted into `name`.
+pub fn is_writable(name: char) -> bool {
+    name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_' | '+' | '*')
+}
+
+impl Registers {
+    pub fn get(&self, name: char) -> Option<&Register> {
+        match name {
+            '"' => self.unnamed.as_ref(),
+            _ => self.stored.get(&name.to_ascii_lowercase()),
+        }
+    }
+
+    /// Stores yanked text: in `name` if given, otherwise in `"0`.
+    pub fn yank(&mut self, name: Option<char>, reg: Register) {
+        match name.filter(|&c| c != '"') {
+            Some(c) => self.write(c, reg),
+            None => {
+                self.stored.insert('0', reg.clone());
+                self.unnamed = Some(reg);
+            }
+        }
+    }
+
+    /// Stores deleted text: in `name` if given, otherwise in `"1` (shifting
+    /// the older deletes down to `"9`) when it spans lines, or in `"-`.
+    pub fn delete(&mut self, name: Option<char>, reg: Register) {
+        match name.filter(|&c| c != '"') {
+            Some(c) => self.write(c, reg),
+            None => {
+                if reg.kind != ClipboardKind::Charwise || reg.text.contains('\n') {
+                    for n in (b'1'..b'9').rev() {
+                        if let Some(r) = self.stored.remove(&(n as char)) {
+                            self.stored.insert((n + 1) as char, r);
+                        }
+                    }
+                    self.stored.insert('1', reg.clone());
+                } else {
+                    self.stored.insert('-', reg.clone());
+                }
+                self.unnamed = Some(reg);
+            }
+        }
+    }
+
+    /// Sets one of the read-only registers (`.` or `:`).
+    pub fn set_read_only(&mut self, name: char, text: String) {
+        self.stored
+            .insert(name, Register::new(text, ClipboardKind::Charwise));
+    }
+
+    /// Stores a macro recorded with `q`. Unlike a yank it leaves `""` alone
+    /// unless it was recorded into `""` itself.
+    pub fn record(&mut self, name: char, keys: String) {
+        let reg = Register::new(keys, ClipboardKind::Charwise);
+        if name == '"' {
+            self.unnamed = Some(reg);
+        } else if name.is_ascii_alphanumeric() {
+            self.store(name, reg);
+        }
+    }
+
+    /// Writes register `name`; an uppercase name appends to its lowercase one.
+    fn write(&mut self, name: char, reg: Register) {
+        if name == '_' || !is_writable(name) {
+            return;
+        }
+        let reg = self.store(name, reg);
+        self.unnamed = Some(reg);
+    }
+
+    fn store(&mut self, name: char, reg: Register) -> Register {
+        let lower = name.to_ascii_lowercase();
+        let reg = match self.stored.remove(&lower) {
+            Some(old) if name.is_ascii_uppercase() => Self::append(old, reg),
+            _ => reg,
+        };
+        self.stored.insert(lower, reg.clone());
+        reg
+    }
+
+    /// Appending linewise text (or to a linewise register) starts a new line.
+    fn append(old: Register, new: Register) -> Register {
+        if old.kind == ClipboardKind::Charwise && new.kind == ClipboardKind::Charwise {
+            return Register::new(old.text + &new.text, ClipboardKind::Charwise);
+        }
+        let kind = match (old.kind, new.kind) {
+            (ClipboardKind::Blockwise, ClipboardKind::Blockwise) => ClipboardKind::Blockwise,
+            _ => ClipboardKind::Linewise,
+        };
+        Register::new(format!("{}\n{}", old.text, new.text), kind)
+    }
+
+    /// Lines of `:registers`, limited to the names in `filter` if it is not
+    /// empty. `extra` holds the registers derived from the editor state.
+    pub fn list(&self, filter: &str, extra: &[(char, Register)]) -> Vec<String> {
+        let mut out = vec!["Type Name Content".to_string()];
+        for name in LIST_ORDER.chars() {
+            if !filter.is_empty() && !filter.contains(name) {
+                continue;
+            }
+            let reg = self
+                .get(name)
+                .or_else(|| extra.iter().find(|(n, _)| *n == name).map(|(_, r)| r));
+            let Some(reg) = reg.filter(|r| !r.text.is_empty()) else {
+                continue;
+            };
+            let (kind, nl) = match reg.kind {
+                ClipboardKind::Charwise => ('c', ""),
+                ClipboardKind::Linewise => ('l', "^J"),
+                ClipboardKind::Blockwise => ('b', ""),
+            };
+            // Control chars (line breaks, keys in macros) as `^J`, `^[`, ...
+            let mut text = String::with_capacity(reg.text.len());
+            for c in reg.text.chars() {
+                if c.is_ascii_control() {
+                    text.push('^');
+                    text.push((c as u8 ^ 0x40) as char);
+                } else {
+                    text.push(c);
+                }
+            }
+            out.push(format!("  {}  \"{}   {}{}", kind, name, text, nl));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(r: &Registers, name: char) -> Option<&str> {
+        r.get(name).map(|r| r.text.as_str())
+    }
+
+    #[test]
+    fn yank_delete_and_numbered_history() {
+        let mut r = Registers::default();
+        r.yank(None, Register::new("kept", ClipboardKind::Charwise));
+        r.delete(None, Register::new("word", ClipboardKind::Charwise));
+        assert_eq!(text(&r, '0'), Some("kept"));
+        assert_eq!(text(&r, '-'), Some("word"));
+        assert_eq!(text(&r, '"'), Some("word"));
+        for i in 1..=10 {
+            r.delete(
+                None,
+                Register::new(format!("line {}", i), ClipboardKind::Linewise),
+            );
+        }
+        assert_eq!(text(&r, '1'), Some("line 10"));
+        assert_eq!(text(&r, '9'), Some("line 2"));
+        assert_eq!(text(&r, '0'), Some("kept"));
+        // Black hole leaves everything alone
+        r.delete(Some('_'), Register::new("gone", ClipboardKind::Linewise));
+        assert_eq!(text(&r, '"'), Some("line 10"));
+        assert_eq!(text(&r, '1'), Some("line 10"));
+    }
+
+    #[test]
+    fn named_registers_and_append() {
+        let mut r = Registers::default();
+        r.yank(Some('a'), Register::new("foo", ClipboardKind::Charwise));
+        r.yank(Some('A'), Register::new("bar", ClipboardKind::Charwise));
+        assert_eq!(text(&r, 'a'), Some("foobar"));
+        assert_eq!(text(&r, '"'), Some("foobar"));
+        assert_eq!(text(&r, '0'), None);
+        r.delete(Some('A'), Register::new("line", ClipboardKind::Linewise));
+        assert_eq!(
+            r.get('a'),
+            Some(&Register::new("foobar\nline", ClipboardKind::Linewise))
+        );
+        assert_eq!(text(&r, '1'), None);
+        r.yank(Some('.'), Register::new("x", ClipboardKind::Charwise));
+        assert_eq!(text(&r, '.'), None);
+
+        r.set_read_only('.', "typed".into());
+        let extra = [('%', Register::new("f.txt", ClipboardKind::Charwise))];
+        assert_eq!(
+            r.list("", &extra),
+            vec![
+                "Type Name Content",
+                "  l  \"\"   foobar^Jline^J",
+                "  l  \"a   foobar^Jline^J",
+                "  c  \".   typed",
+                "  c  \"%   f.txt",
+            ]
+        );
+        assert_eq!(r.list("%", &extra).len(), 2);
+
+        // Recording a macro leaves `""` alone
+        r.record('q', "ix\x1b".into());
+        r.record('Q', "j".into());
+        assert_eq!(text(&r, '"'), Some("foobar\nline"));
+        assert_eq!(
+            r.list("q", &[]),
+            vec!["Type Name Content", "  c  \"q   ix^[j"]
+        );
+    }
+}
@@ -0,0 +1,828 @@
+//! Text objects: the `iw`, `a(`, `it`, ... that follow an operator or extend
+//! a Visual selection, and the motions that share their idea of words,
+//! sentences, paragraphs and brackets (`W`, `ge`, `)`, `}`, `%`, ...). They
+//! work on char indices into the buffer.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::buffer::Buffer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// Chars `start..end`.
+    Chars(usize, usize),
+    /// Whole lines `first..=last`.
+    Lines(usize, usize),
+}
+
+/// The object `obj` (`w`, `"`, `(`, `t`, ...) around char index `cursor`.
+/// `around` is the `a` variant, `i` otherwise. `None` if there is no such
+/// object at the cursor.
+pub fn select(
+    buf: &Buffer,
+    cursor: usize,
+    obj: char,
+    around: bool,
+    count: usize,
+) -> Option<Selection> {
+    let count = count.max(1);
+    match obj {
+        'w' => word(buf, cursor, count, around, false),
+        'W' => word(buf, cursor, count, around, true),
+        's' => sentence(buf, cursor, count, around),
+        'p' => paragraph(buf, cursor, count, around),
+        '"' | '\'' | '`' => quote(buf, cursor, obj, around),
+        '(' | ')' | 'b' => bracket(buf, cursor, '(', ')', around, count),
+        '[' | ']' => bracket(buf, cursor, '[', ']', around, count),
+        '{' | '}' | 'B' => bracket(buf, cursor, '{', '}', around, count),
+        '<' | '>' => bracket(buf, cursor, '<', '>', around, count),
+        't' => tag(buf, cursor, around, count),
+        _ => None,
+    }
+}
+
+/// Char range of the line holding `idx`, without its line break.
+fn line_bounds(buf: &Buffer, idx: usize) -> (usize, usize) {
+    let (y, _) = buf.char_to_pos(idx);
+    let start = buf.line_start_char(y);
+    (start, start + buf.line_string(y).chars().count())
+}
+
+fn is_blank_line(buf: &Buffer, y: usize) -> bool {
+    buf.line_string(y).trim().is_empty()
+}
+
+/// 0 for white space, 1 for punctuation, 2 for word chars. With `big`
+/// (WORDs) everything that isn't white space is 2.
+fn char_class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        2
+    } else {
+        1
+    }
+}
+
+/// `iw` selects `count` runs of word chars, punctuation or white space;
+/// `aw` a word with the white space after it, or before it when there is
+/// none after (or when starting on white space).
+fn word(buf: &Buffer, cursor: usize, count: usize, around: bool, big: bool) -> Option<Selection> {
+    let (ls, le) = line_bounds(buf, cursor);
+    if cursor >= le {
+        return None;
+    }
+    let class = |i: usize| buf.char_at(i).map_or(0, |c| char_class(c, big));
+    let run_end = |i: usize| {
+        let c = class(i);
+        let mut j = i;
+        while j < le && class(j) == c {
+            j += 1;
+        }
+        j
+    };
+    let mut start = cursor;
+    while start > ls && class(start - 1) == class(cursor) {
+        start -= 1;
+    }
+    let mut end = start;
+    let mut white_after = false;
+    for _ in 0..count {
+        if end >= le {
+            break;
+        }
+        if !around {
+            end = run_end(end);
+        } else if class(end) == 0 {
+            end = run_end(end);
+            if end < le {
+                end = run_end(end);
+            }
+        } else {
+            end = run_end(end);
+            if end < le && class(end) == 0 {
+                end = run_end(end);
+                white_after = true;
+            } else if !white_after && start > ls && class(start - 1) == 0 {
+                // No white space after: take the white space before
+                while start > ls && class(start - 1) == 0 {
+                    start -= 1;
+                }
+            }
+        }
+    }
+    Some(Selection::Chars(start, end))
+}
+
+/// Whether char `idx` is the line break of an empty line, which word
+/// motions stop at like at a word.
+fn is_empty_line(buf: &Buffer, idx: usize) -> bool {
+    buf.char_at(idx) == Some('\n') && (idx == 0 || buf.char_at(idx - 1) == Some('\n'))
+}
+
+/// `w`/`W`: start of the next word after `idx`, or the end of the buffer.
+pub fn word_forward(buf: &Buffer, idx: usize, big: bool) -> usize {
+    let len = buf.len_chars();
+    let class = |i: usize| buf.char_at(i).map_or(0, |c| char_class(c, big));
+    let mut i = idx;
+    let c = class(i);
+    if c != 0 {
+        while i < len && class(i) == c {
+            i += 1;
+        }
+    }
+    while i < len && class(i) == 0 && !(i > idx && is_empty_line(buf, i)) {
+        i += 1;
+    }
+    i
+}
+
+/// `b`/`B`: start of the word before `idx` (or holding it).
+pub fn word_backward(buf: &Buffer, idx: usize, big: bool) -> usize {
+    let class = |i: usize| buf.char_at(i).map_or(0, |c| char_class(c, big));
+    let mut i = idx;
+    loop {
+        if i == 0 {
+            return 0;
+        }
+        i -= 1;
+        if class(i) != 0 || is_empty_line(buf, i) {
+            break;
+        }
+    }
+    let c = class(i);
+    while c != 0 && i > 0 && class(i - 1) == c {
+        i -= 1;
+    }
+    i
+}
+
+/// `e`/`E`: last char of the word after `idx` (or holding it), crossing
+/// empty lines.
+pub fn word_end(buf: &Buffer, idx: usize, big: bool) -> usize {
+    let len = buf.len_chars();
+    let class = |i: usize| buf.char_at(i).map_or(0, |c| char_class(c, big));
+    let mut i = idx + 1;
+    while i < len && class(i) == 0 {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1).max(idx);
+    }
+    let c = class(i);
+    while i + 1 < len && class(i + 1) == c {
+        i += 1;
+    }
+    i
+}
+
+/// `ge`/`gE`: last char of the word before the one holding `idx`.
+pub fn word_end_backward(buf: &Buffer, idx: usize, big: bool) -> usize {
+    let class = |i: usize| buf.char_at(i).map_or(0, |c| char_class(c, big));
+    let mut i = idx;
+    let c = class(i);
+    while c != 0 && i > 0 && class(i - 1) == c {
+        i -= 1;
+    }
+    loop {
+        if i == 0 {
+            return 0;
+        }
+        i -= 1;
+        if class(i) != 0 || is_empty_line(buf, i) {
+            return i;
+        }
+    }
+}
+
+/// End of the sentence starting at `from`: after `.`, `!` or `?` (and any
+/// closing `)`, `]`, quotes) followed by white space, at an empty line, or
+/// at the end of the buffer.
+fn sentence_end(buf: &Buffer, from: usize) -> usize {
+    let len = buf.len_chars();
+    let mut i = from;
+    while i < len {
+        let c = buf.char_at(i).unwrap_or(' ');
+        if c == '\n' && buf.char_at(i + 1) == Some('\n') {
+            return i;
+        }
+        if matches!(c, '.' | '!' | '?') {
+            let mut j = i + 1;
+            while matches!(buf.char_at(j), Some(')' | ']' | '"' | '\'')) {
+                j += 1;
+            }
+            if buf.char_at(j).is_none_or(char::is_whitespace) {
+                return j;
+            }
+            i = j;
+            continue;
+        }
+        i += 1;
+    }
+    len
+}
+
+/// Whether a sentence ends right before `idx`: at `.`, `!` or `?`, maybe
+/// followed by closing `)`, `]` or quotes.
+fn ends_sentence(buf: &Buffer, idx: usize) -> bool {
+    let mut k = idx;
+    while k > 0 && matches!(buf.char_at(k - 1), Some(')' | ']' | '"' | '\'')) {
+        k -= 1;
+    }
+    k > 0 && matches!(buf.char_at(k - 1), Some('.' | '!' | '?'))
+}
+
+/// Whether `idx` starts a paragraph (follows an empty line, or is the start).
+fn starts_paragraph(buf: &Buffer, idx: usize) -> bool {
+    idx == 0
+        || (buf.char_at(idx - 1) == Some('\n') && (idx < 2 || buf.char_at(idx - 2) == Some('\n')))
+}
+
+/// Start of the white space run holding `idx`, within the paragraph.
+fn white_start(buf: &Buffer, mut idx: usize) -> usize {
+    while !starts_paragraph(buf, idx) && buf.char_at(idx - 1).is_some_and(char::is_whitespace) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Start of the sentence holding `idx`.
+fn sentence_start(buf: &Buffer, idx: usize) -> usize {
+    let mut start = idx;
+    while !starts_paragraph(buf, start) {
+        if buf.char_at(start - 1).is_some_and(char::is_whitespace) {
+            let white = white_start(buf, start - 1);
+            if starts_paragraph(buf, white) || ends_sentence(buf, white) {
+                break;
+            }
+        }
+        start -= 1;
+    }
+    start
+}
+
+/// White space after `idx`, stopping before an empty line.
+fn skip_white(buf: &Buffer, mut idx: usize) -> usize {
+    while let Some(c) = buf.char_at(idx) {
+        if !c.is_whitespace() || (c == '\n' && buf.char_at(idx + 1) == Some('\n')) {
+            break;
+        }
+        idx += 1;
+    }
+    idx
+}
+
+/// Whether a sentence starts at `idx`: the first char after a sentence end
+/// and white space, or of a paragraph. An empty line counts as a sentence.
+fn is_sentence_start(buf: &Buffer, idx: usize) -> bool {
+    match buf.char_at(idx) {
+        None => false,
+        Some('\n') => is_empty_line(buf, idx),
+        Some(c) if c.is_whitespace() => false,
+        Some(_) => {
+            starts_paragraph(buf, idx)
+                || (buf.char_at(idx - 1).is_some_and(char::is_whitespace) && {
+                    let white = white_start(buf, idx - 1);
+                    starts_paragraph(buf, white) || ends_sentence(buf, white)
+                })
+        }
+    }
+}
+
+/// `)`: start of the next sentence, or the end of the buffer.
+pub fn sentence_forward(buf: &Buffer, idx: usize) -> usize {
+    let len = buf.len_chars();
+    (idx + 1..len)
+        .find(|&i| is_sentence_start(buf, i))
+        .unwrap_or(len)
+}
+
+/// `(`: start of the sentence holding `idx`, or of the one before if `idx`
+/// already is a start.
+pub fn sentence_backward(buf: &Buffer, idx: usize) -> usize {
+    (0..idx)
+        .rev()
+        .find(|&i| is_sentence_start(buf, i))
+        .unwrap_or(0)
+}
+
+/// `}`: the blank line after the paragraph at (or after) line `y`; `None`
+/// if the paragraph runs to the end of the buffer.
+pub fn paragraph_forward(buf: &Buffer, y: usize) -> Option<usize> {
+    let count = buf.line_count();
+    let mut l = y;
+    while l < count && is_blank_line(buf, l) {
+        l += 1;
+    }
+    while l < count && !is_blank_line(buf, l) {
+        l += 1;
+    }
+    (l < count).then_some(l)
+}
+
+/// `{`: the blank line before the paragraph at (or before) line `y`; `None`
+/// if the paragraph starts the buffer.
+pub fn paragraph_backward(buf: &Buffer, y: usize) -> Option<usize> {
+    let mut l = y;
+    while is_blank_line(buf, l) {
+        l = l.checked_sub(1)?;
+    }
+    while !is_blank_line(buf, l) {
+        l = l.checked_sub(1)?;
+    }
+    Some(l)
+}
+
+/// `is` selects a sentence (or the white space between two), `as` a
+/// sentence with the white space after it, or before it when there is none.
+fn sentence(buf: &Buffer, cursor: usize, count: usize, around: bool) -> Option<Selection> {
+    // White space between two sentences is an object of its own
+    let white = white_start(buf, cursor);
+    let on_white = buf.char_at(cursor)?.is_whitespace()
+        && (starts_paragraph(buf, white) || ends_sentence(buf, white));
+    let mut start = if on_white {
+        white
+    } else {
+        sentence_start(buf, cursor)
+    };
+    let mut end = start;
+    for _ in 0..count {
+        if on_white {
+            end = skip_white(buf, end);
+            if around {
+                end = sentence_end(buf, end);
+            }
+        } else {
+            end = sentence_end(buf, end);
+            if around {
+                let white_end = skip_white(buf, end);
+                if white_end > end {
+                    end = white_end;
+                } else {
+                    start = white_start(buf, start);
+                }
+            } else if count > 1 {
+                end = skip_white(buf, end);
+            }
+        }
+    }
+    Some(Selection::Chars(start, end))
+}
+
+/// `ip` selects the paragraph (or run of blank lines) holding the cursor,
+/// `ap` adds the blank lines after it, or before it at the end of the file.
+fn paragraph(buf: &Buffer, cursor: usize, count: usize, around: bool) -> Option<Selection> {
+    let (cy, _) = buf.char_to_pos(cursor);
+    let last = buf.line_count().saturating_sub(1);
+    let run_end = |y: usize| {
+        let blank = is_blank_line(buf, y);
+        let mut e = y;
+        while e < last && is_blank_line(buf, e + 1) == blank {
+            e += 1;
+        }
+        e
+    };
+    let blank = is_blank_line(buf, cy);
+    let mut first = cy;
+    while first > 0 && is_blank_line(buf, first - 1) == blank {
+        first -= 1;
+    }
+    let mut end = run_end(cy);
+    let mut runs = if around { 2 * count - 1 } else { count - 1 };
+    while runs > 0 && end < last {
+        end = run_end(end + 1);
+        runs -= 1;
+    }
+    if around && runs > 0 && !blank {
+        // Nothing after the paragraph: take the blank lines before it
+        while first > 0 && is_blank_line(buf, first - 1) {
+            first -= 1;
+        }
+    }
+    Some(Selection::Lines(first, end))
+}
+
+/// Unescaped `q` quotes on the line, as char indices.
+fn quotes_on_line(buf: &Buffer, ls: usize, le: usize, q: char) -> Vec<usize> {
+    let mut out = Vec::new();
+    let mut escaped = false;
+    for i in ls..le {
+        let c = buf.char_at(i).unwrap_or(' ');
+        if c == q && !escaped {
+            out.push(i);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    out
+}
+
+/// `i"` selects the text between two quotes on the line, `a"` the quotes
+/// too and the white space after them (or before, if there is none after).
+/// On a quote, the quotes pair up from the start of the line.
+fn quote(buf: &Buffer, cursor: usize, q: char, around: bool) -> Option<Selection> {
+    let (ls, le) = line_bounds(buf, cursor);
+    let quotes = quotes_on_line(buf, ls, le, q);
+    let (open, close) = match quotes.iter().position(|&i| i == cursor) {
+        Some(n) if n % 2 == 0 => (cursor, *quotes.get(n + 1)?),
+        Some(n) => (quotes[n - 1], cursor),
+        None => match quotes.iter().rposition(|&i| i < cursor) {
+            Some(n) => (quotes[n], *quotes.get(n + 1)?),
+            None => (*quotes.first()?, *quotes.get(1)?),
+        },
+    };
+    if !around {
+        return Some(Selection::Chars(open + 1, close));
+    }
+    let white = |i: usize| buf.char_at(i).is_some_and(|c| c == ' ' || c == '\t');
+    let (mut start, mut end) = (open, close + 1);
+    if end < le && white(end) {
+        while end < le && white(end) {
+            end += 1;
+        }
+    } else {
+        while start > ls && white(start - 1) {
+            start -= 1;
+        }
+    }
+    Some(Selection::Chars(start, end))
+}
+
+/// Unmatched `open` at or before `from`. A `close` right at `from` is
+/// skipped when `from_inside` is set (the cursor is on the closing one).
+fn find_open(
+    buf: &Buffer,
+    from: usize,
+    open: char,
+    close: char,
+    from_inside: bool,
+) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = from + 1;
+    while i > 0 {
+        i -= 1;
+        let c = buf.char_at(i)?;
+        if c == close && !(from_inside && i == from) {
+            depth += 1;
+        } else if c == open {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+fn find_close(buf: &Buffer, open_idx: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = open_idx + 1;
+    while let Some(c) = buf.char_at(i) {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Pairs that `%` jumps between.
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+fn is_bracket(c: char) -> bool {
+    BRACKETS.iter().any(|&(o, cl)| c == o || c == cl)
+}
+
+/// The bracket matching the one at char `idx`, looked for within the chars
+/// in `bounds` only (the UI passes the visible ones).
+pub fn matching_bracket(buf: &Buffer, idx: usize, bounds: Range<usize>) -> Option<usize> {
+    let c = buf.char_at(idx)?;
+    let &(open, close) = BRACKETS.iter().find(|&&(o, cl)| c == o || c == cl)?;
+    let mut depth = 0usize;
+    let mut step = |i: usize| {
+        let ch = buf.char_at(i);
+        if ch == Some(c) {
+            depth += 1;
+        } else if ch == Some(if c == open { close } else { open }) {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+        false
+    };
+    if c == open {
+        (idx + 1..bounds.end.min(buf.len_chars())).find(|&i| step(i))
+    } else {
+        (bounds.start..idx).rev().find(|&i| step(i))
+    }
+}
+
+/// `%`: the first bracket at or after `cursor` on its line, and the one
+/// matching it.
+pub fn match_pair(buf: &Buffer, cursor: usize) -> Option<(usize, usize)> {
+    let (_, end) = line_bounds(buf, cursor);
+    let at = (cursor..end).find(|&i| buf.char_at(i).is_some_and(is_bracket))?;
+    let other = matching_bracket(buf, at, 0..buf.len_chars())?;
+    Some((at, other))
+}
+
+/// `i(` selects inside the `count`-th enclosing pair, `a(` the brackets
+/// too. When the brackets sit on their own lines the inner part is those
+/// lines in between, linewise.
+fn bracket(
+    buf: &Buffer,
+    cursor: usize,
+    open: char,
+    close: char,
+    around: bool,
+    count: usize,
+) -> Option<Selection> {
+    let mut o = find_open(buf, cursor, open, close, true)?;
+    for _ in 1..count {
+        o = find_open(buf, o.checked_sub(1)?, open, close, false)?;
+    }
+    let c = find_close(buf, o, open, close)?;
+    if around {
+        return Some(Selection::Chars(o, c + 1));
+    }
+    let (open_line, _) = buf.char_to_pos(o);
+    let (close_line, _) = buf.char_to_pos(c);
+    let close_indented = buf.string_from_char_range(buf.line_start_char(close_line), c);
+    if buf.char_at(o + 1) == Some('\n') && close_indented.trim().is_empty() {
+        if close_line > open_line + 1 {
+            return Some(Selection::Lines(open_line + 1, close_line - 1));
+        }
+        return Some(Selection::Chars(o + 1, o + 1));
+    }
+    Some(Selection::Chars(o + 1, c))
+}
+
+/// An XML/HTML tag starting at the `<` at `start`.
+struct Tag {
+    name: String,
+    closing: bool,
+    /// Index just after the `>`.
+    end: usize,
+}
+
+fn parse_tag(buf: &Buffer, start: usize) -> Option<Tag> {
+    let mut i = start + 1;
+    let closing = buf.char_at(i) == Some('/');
+    if closing {
+        i += 1;
+    }
+    let mut name = String::new();
+    while let Some(c) = buf.char_at(i) {
+        if c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.') {
+            name.push(c);
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if name.is_empty() {
+        return None;
+    }
+    let mut prev = ' ';
+    while let Some(c) = buf.char_at(i) {
+        match c {
+            '>' if prev == '/' => return None,
+            '>' => {
+                return Some(Tag {
+                    name,
+                    closing,
+                    end: i + 1,
+                })
+            }
+            '<' => return None,
+            _ => {}
+        }
+        prev = c;
+        i += 1;
+    }
+    None
+}
+
+/// An element: where its opening tag starts and ends, and where its
+/// closing tag starts and ends.
+type Element = (usize, usize, usize, usize);
+
+/// Every element in the buffer, found in one pass. A closing tag ends the
+/// last element of that name still open; tags never closed (`<br>`, `<li>`)
+/// make no element.
+fn elements(buf: &Buffer) -> Vec<Element> {
+    let mut open: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while let Some(c) = buf.char_at(i) {
+        if c == '<' {
+            if let Some(t) = parse_tag(buf, i) {
+                if !t.closing {
+                    open.entry(t.name).or_default().push((i, t.end));
+                } else if let Some((start, end)) = open.get_mut(&t.name).and_then(Vec::pop) {
+                    found.push((start, end, i, t.end));
+                }
+                i = t.end;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+/// `it` selects the contents of the `count`-th enclosing element, `at` the
+/// element with its tags. A cursor on an opening or closing tag is inside
+/// that element.
+fn tag(buf: &Buffer, cursor: usize, around: bool, count: usize) -> Option<Selection> {
+    // Start scanning back from the end of a tag the cursor is on
+    let mut scan = cursor + 1;
+    if let Some(lt) = (0..=cursor)
+        .rev()
+        .find(|&i| matches!(buf.char_at(i), Some('<' | '>')))
+    {
+        if buf.char_at(lt) == Some('<') {
+            if let Some(t) = parse_tag(buf, lt).filter(|t| t.end > cursor) {
+                scan = if t.closing { lt } else { t.end };
+            }
+        }
+    }
+    // Elements opened before the scan point and closed after it, innermost
+    // first
+    let mut enclosing: Vec<Element> = elements(buf)
+        .into_iter()
+        .filter(|&(start, _, close_start, close_end)| {
+            start < scan && close_start >= scan && close_end > cursor
+        })
+        .collect();
+    enclosing.sort_by_key(|&(start, ..)| Reverse(start));
+    let &(start, end, close_start, close_end) = enclosing.get(count - 1)?;
+    Some(if around {
+        Selection::Chars(start, close_end)
+    } else {
+        Selection::Chars(end, close_start)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buf(text: &str) -> Buffer {
+        Buffer::from_lines(text.split('\n').map(String::from).collect())
+    }
+
+    /// The text an object selects with the cursor on the first `^` in `text`
+    /// (the marker itself is removed).
+    fn pick(text: &str, obj: char, around: bool, count: usize) -> Option<String> {
+        let cursor = text.find('^').unwrap();
+        let text = text.replacen('^', "", 1);
+        let b = buf(&text);
+        let cursor = text[..cursor].chars().count();
+        Some(match select(&b, cursor, obj, around, count)? {
+            Selection::Chars(s, e) => b.string_from_char_range(s, e),
+            Selection::Lines(f, l) => format!("lines {}-{}", f, l),
+        })
+    }
+
+    #[test]
+    fn words_and_sentences() {
+        let s = |t, obj, around, count| pick(t, obj, around, count).unwrap();
+        assert_eq!(s("foo b^ar baz", 'w', false, 1), "bar");
+        assert_eq!(s("foo b^ar baz", 'w', true, 1), "bar ");
+        assert_eq!(s("foo ba^r", 'w', true, 1), " bar");
+        assert_eq!(s("foo b^ar baz", 'w', false, 3), "bar baz");
+        assert_eq!(s("f^(x).y z", 'w', false, 1), "(");
+        assert_eq!(s("f^(x).y z", 'W', false, 1), "f(x).y");
+        assert_eq!(s("foo ^ bar", 'w', true, 1), "  bar");
+
+        let text = "One here. Two ^is here! Three.";
+        assert_eq!(s(text, 's', false, 1), "Two is here!");
+        assert_eq!(s(text, 's', true, 1), "Two is here! ");
+        assert_eq!(s("A. B^ c", 's', true, 1), " B c");
+        assert_eq!(s("First\nline^s. Next", 's', false, 1), "First\nlines.");
+        assert_eq!(s("One.  ^ Two.", 's', false, 1), "   ");
+        assert_eq!(s("One.  ^ Two.", 's', true, 1), "   Two.");
+        assert_eq!(s("Para.\n\n^Next one.", 's', true, 1), "Next one.");
+    }
+
+    #[test]
+    fn paragraphs_are_linewise() {
+        let text = "a\nb^\n\n\nc\nd\n\ne";
+        assert_eq!(pick(text, 'p', false, 1).unwrap(), "lines 0-1");
+        assert_eq!(pick(text, 'p', true, 1).unwrap(), "lines 0-3");
+        assert_eq!(pick(text, 'p', false, 2).unwrap(), "lines 0-3");
+        assert_eq!(pick(text, 'p', true, 2).unwrap(), "lines 0-6");
+        // Last paragraph: the blank lines before it instead
+        assert_eq!(pick("a\n\n^e", 'p', true, 1).unwrap(), "lines 1-2");
+    }
+
+    #[test]
+    fn quotes_and_brackets() {
+        let s = |t, obj, around, count| pick(t, obj, around, count);
+        assert_eq!(s("x = \"a ^b\" + 1", '"', false, 1).unwrap(), "a b");
+        assert_eq!(s("x = \"a ^b\" + 1", '"', true, 1).unwrap(), "\"a b\" ");
+        assert_eq!(s("say 'it\\'s' ^now", '\'', false, 1), None);
+        assert_eq!(s("^x = `a` `b`", '`', false, 1).unwrap(), "a");
+        assert_eq!(s("\"a\" ^\"b\"", '"', false, 1).unwrap(), "b");
+
+        let code = "f(a, g(b^, c), d)";
+        assert_eq!(s(code, '(', false, 1).unwrap(), "b, c");
+        assert_eq!(s(code, 'b', true, 1).unwrap(), "(b, c)");
+        assert_eq!(s(code, ')', false, 2).unwrap(), "a, g(b, c), d");
+        assert_eq!(s("(a) ^b", '(', false, 1), None);
+        assert_eq!(s("f(a^)", '(', false, 1).unwrap(), "a");
+        assert_eq!(s("x[^]", '[', false, 1).unwrap(), "");
+        assert_eq!(s("<a ^b>", '<', true, 1).unwrap(), "<a b>");
+        // Brackets on their own lines: the lines between
+        let block = "fn f() {\n    a(\n      1^);\n    b\n}";
+        assert_eq!(s(block, '{', false, 1).unwrap(), "lines 1-3");
+        assert_eq!(s(block, '(', false, 1).unwrap(), "\n      1");
+        assert_eq!(
+            s(block, 'B', true, 1).unwrap(),
+            "{\n    a(\n      1);\n    b\n}"
+        );
+    }
+
+    #[test]
+    fn tags_nest_across_lines() {
+        let html = "<div id=\"x\">\n  <p>one <b>t^wo</b></p>\n  <br/>\n</div>";
+        let s = |t, around, count| pick(t, 't', around, count);
+        assert_eq!(s(html, false, 1).unwrap(), "two");
+        assert_eq!(s(html, true, 1).unwrap(), "<b>two</b>");
+        assert_eq!(s(html, false, 2).unwrap(), "one <b>two</b>");
+        assert_eq!(
+            s(html, false, 3).unwrap(),
+            "\n  <p>one <b>two</b></p>\n  <br/>\n"
+        );
+        assert_eq!(s(html, false, 4), None);
+        // On a tag: that element
+        assert_eq!(s("<p>a <^b>x</b></p>", false, 1).unwrap(), "x");
+        assert_eq!(s("<p>a <b>x</^b></p>", true, 1).unwrap(), "<b>x</b>");
+        assert_eq!(s("<p>a</p> ^b <p>c</p>", false, 1), None);
+        // Tags that are never closed are not elements
+        assert_eq!(
+            s("<ul><li>a<li>b^c<br></ul>", false, 1).unwrap(),
+            "<li>a<li>bc<br>"
+        );
+        assert_eq!(s("<p>x <img src=y> ^z", false, 1), None);
+    }
+
+    #[test]
+    fn percent_finds_matching_brackets() {
+        let b = buf("if (a[0] {\n  x(y)\n}) z");
+        // On a bracket, or before one on the line
+        assert_eq!(match_pair(&b, 3), Some((3, 19)));
+        assert_eq!(match_pair(&b, 19), Some((19, 3)));
+        assert_eq!(match_pair(&b, 0), Some((3, 19)));
+        assert_eq!(match_pair(&b, 9), Some((9, 18)));
+        assert_eq!(match_pair(&b, 5), Some((5, 7)));
+        assert_eq!(match_pair(&b, 21), None);
+        // Outside the bounds a match isn't found
+        assert_eq!(matching_bracket(&b, 9, 0..15), None);
+        assert_eq!(matching_bracket(&b, 14, 12..17), Some(16));
+    }
+
+    #[test]
+    fn word_sentence_and_paragraph_motions() {
+        let b = buf("a.b c-d\n\n  e");
+        assert_eq!(word_forward(&b, 0, true), 4);
+        assert_eq!(word_forward(&b, 4, true), 8);
+        assert_eq!(word_forward(&b, 8, true), 11);
+        assert_eq!(word_backward(&b, 11, true), 8);
+        assert_eq!(word_backward(&b, 8, true), 4);
+        assert_eq!(word_backward(&b, 6, true), 4);
+        assert_eq!(word_end(&b, 0, true), 2);
+        assert_eq!(word_end(&b, 2, true), 6);
+        assert_eq!(word_end(&b, 6, true), 11);
+        assert_eq!(word_end_backward(&b, 6, false), 5);
+        assert_eq!(word_end_backward(&b, 4, false), 2);
+        assert_eq!(word_end_backward(&b, 11, false), 8);
+
+        let b = buf("One. Two (x).  Three\nstill?\n\nNext");
+        assert_eq!(sentence_forward(&b, 0), 5);
+        assert_eq!(sentence_forward(&b, 6), 15);
+        assert_eq!(sentence_forward(&b, 15), 28);
+        assert_eq!(sentence_forward(&b, 28), 29);
+        assert_eq!(sentence_forward(&b, 29), 33);
+        assert_eq!(sentence_backward(&b, 29), 28);
+        assert_eq!(sentence_backward(&b, 20), 15);
+        assert_eq!(sentence_backward(&b, 15), 5);
+        assert_eq!(sentence_backward(&b, 2), 0);
+
+        let b = buf("a\nb\n\n\nc\n\nd");
+        assert_eq!(paragraph_forward(&b, 0), Some(2));
+        assert_eq!(paragraph_forward(&b, 2), Some(5));
+        assert_eq!(paragraph_forward(&b, 5), None);
+        assert_eq!(paragraph_backward(&b, 6), Some(5));
+        assert_eq!(paragraph_backward(&b, 4), Some(3));
+        assert_eq!(paragraph_backward(&b, 3), None);
+    }
+}
@@ -1,8 +1,10 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::{self, Write};
 use std::time::{Duration, Instant};
 
 use crossterm::cursor::{MoveTo, Show};
-use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::queue;
 use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor};
 use crossterm::terminal::{self, Clear, ClearType};
@@ -10,18 +12,25 @@ use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
 use crate::buffer::Buffer;
-use crate::editor::Editor;
+use crate::editor::{Editor, WindowView};
+use crate::input;
+use crate::window::Rect;
 
 const STATUS_TIMEOUT_MS: u64 = 2000;
 
 pub struct Ui {
     pub screen_cols: u16,
     pub screen_rows: u16, // excluding status row
-    pub off_x: usize,
-    pub off_y: usize,
-    prev_lines: Vec<String>,
-    prev_status: String,
+    /// What was last drawn at each window row, by its screen position.
+    prev_lines: HashMap<(usize, usize), String>,
+    /// Last status line drawn at each position, and whether it was the
+    /// current window's.
+    prev_status: HashMap<(usize, usize), (bool, String)>,
     prev_message: String,
+    /// Window layout last drawn; any change repaints the whole screen.
+    prev_rects: Vec<Rect>,
+    /// Tab page labels last drawn on the tab line.
+    prev_tabs: Vec<(String, bool)>,
 }
 
 impl Ui {
@@ -41,53 +50,91 @@ impl Ui {
         }
         out
     }
+    /// Text drawn for grapheme `g` occupying `gw` cells: tabs expand to spaces,
+    /// control characters use caret notation (`^M`) and raw bytes hex (`<ff>`).
+    fn render_grapheme(g: &str, gw: usize) -> Cow<'_, str> {
+        if g == "\t" {
+            Cow::Owned(" ".repeat(gw))
+        } else if let Some(c) = Buffer::control_char(g) {
+            let shown = char::from_u32((c as u32) ^ 0x40).unwrap_or('?');
+            Cow::Owned(format!("^{}", shown))
+        } else if let Some(b) = Buffer::raw_byte(g) {
+            Cow::Owned(format!("<{:02x}>", b))
+        } else {
+            Cow::Borrowed(g)
+        }
+    }
+
     pub fn new() -> io::Result<Self> {
         let (cols, rows) = terminal::size()?;
         Ok(Self {
             screen_cols: cols,
             screen_rows: rows.saturating_sub(1),
-            off_x: 0,
-            off_y: 0,
-            prev_lines: vec![String::new(); rows.saturating_sub(1) as usize],
-            prev_status: String::new(),
+            prev_lines: HashMap::new(),
+            prev_status: HashMap::new(),
             prev_message: String::new(),
+            prev_rects: Vec::new(),
+            prev_tabs: Vec::new(),
         })
     }
 
     pub fn resize(&mut self, w: u16, h: u16) {
         self.screen_cols = w;
         self.screen_rows = h.saturating_sub(1);
-        self.prev_lines = vec![String::new(); self.screen_rows as usize];
-        self.prev_status.clear();
-        self.prev_message.clear();
+        self.clear_cache();
     }
 
-    fn scroll(&mut self, ed: &Editor) {
-        if ed.cy < self.off_y {
-            self.off_y = ed.cy;
+    /// Screen area the windows share. A single window's status line is the
+    /// last row, shared with messages; with more, messages get a row of
+    /// their own. With tab pages the tab line takes the first row.
+    fn window_area(&self, ed: &Editor) -> Rect {
+        let rows = self.screen_rows as usize + usize::from(ed.windows.len() == 1);
+        let tab_line = usize::from(ed.tab_count() > 1);
+        Rect {
+            x: 0,
+            y: tab_line,
+            width: self.screen_cols as usize,
+            height: rows.saturating_sub(tab_line),
         }
-        if ed.cy >= self.off_y + self.screen_rows as usize {
-            self.off_y = ed.cy + 1 - self.screen_rows as usize;
+    }
+
+    /// Follows the cursor: vertically through the editor's viewport, which
+    /// scrolling commands move too, and horizontally through the window.
+    fn scroll(&mut self, ed: &mut Editor) {
+        ed.scroll_to_cursor();
+        let cx = ed.cx;
+        let win = ed.windows.current_mut();
+        let width = win.rect.width.max(1);
+        if cx < win.left {
+            win.left = cx;
         }
-        if ed.cx < self.off_x {
-            self.off_x = ed.cx;
+        if cx >= win.left + width {
+            win.left = cx + 1 - width;
         }
-        if ed.cx >= self.off_x + self.screen_cols as usize {
-            self.off_x = ed.cx + 1 - self.screen_cols as usize;
+    }
+
+    /// Starts drawing line `y` of a window. One that reaches the right edge
+    /// of the screen clears the rest of the line first.
+    fn start_row<W: Write>(&self, w: &mut W, rect: &Rect, y: usize) -> io::Result<()> {
+        queue!(w, MoveTo(rect.x as u16, y as u16))?;
+        if rect.x + rect.width >= self.screen_cols as usize {
+            queue!(w, Clear(ClearType::UntilNewLine))?;
         }
+        Ok(())
     }
 
-    fn draw_rows<W: Write>(&mut self, mut w: W, ed: &Editor) -> io::Result<()> {
-        fn gw_at(col: usize, g: &str) -> usize {
-            if g == "\t" {
-                let ts = Buffer::TABSTOP.max(1);
-                let next = ((col / ts) + 1) * ts;
-                next - col
-            } else {
-                UnicodeWidthStr::width(g).max(1)
-            }
+    /// Blanks the rest of a window line after `used` cells, where clearing
+    /// the line would wipe out the window to its right.
+    fn end_row<W: Write>(&self, w: &mut W, rect: &Rect, used: usize) -> io::Result<()> {
+        if rect.x + rect.width < self.screen_cols as usize && used < rect.width {
+            queue!(w, Print(" ".repeat(rect.width - used)))?;
         }
-        // Determine selection bounds if in Visual mode
+        Ok(())
+    }
+
+    fn draw_rows<W: Write>(&mut self, mut w: W, ed: &Editor, view: &WindowView) -> io::Result<()> {
+        let gw_at = Buffer::gw_at;
+        // Determine selection bounds if the window is in Visual mode
         enum Sel {
             None,
             Char {
@@ -107,7 +154,12 @@ impl Ui {
                 right: usize,
             },
         }
-        let selection = match ed.mode {
+        let mode = if view.current {
+            ed.mode
+        } else {
+            crate::keymap::Mode::Normal
+        };
+        let selection = match mode {
             crate::keymap::Mode::Visual => {
                 if let Some((ax, ay)) = ed.visual_anchor {
                     let (cx, cy) = (ed.cx, ed.cy);
@@ -148,30 +200,60 @@ impl Ui {
             _ => Sel::None,
         };
 
-        for row in 0..self.screen_rows as usize {
-            let file_row = self.off_y + row;
+        let rect = view.rect;
+        let rows = rect.height.saturating_sub(1);
+        let buf = view.buf;
+        let highlight = ed.highlight_regex();
+        // The substitution being confirmed and the bracket pair are only
+        // shown where the cursor is
+        let current = ed.substitute_highlight().filter(|_| view.current);
+        let top = view.top;
+        let brackets = if view.current {
+            ed.bracket_highlight(top..top + rows)
+        } else {
+            Default::default()
+        };
+        for row in 0..rows {
+            let file_row = top + row;
+            let y = rect.y + row;
+            let key = (rect.x, y);
             // When highlighting selection, skip string-diff cache to ensure correct rendering
             let use_cache = matches!(selection, Sel::None);
-            if file_row >= ed.buf.line_count() {
+            if file_row >= buf.line_count() {
                 // Tilde rows
-                if !use_cache || self.prev_lines[row] != "~" {
-                    queue!(
-                        w,
-                        MoveTo(0, row as u16),
-                        Clear(ClearType::CurrentLine),
-                        Print("~")
-                    )?;
-                    self.prev_lines[row] = "~".to_string();
+                if !use_cache || self.prev_lines.get(&key).map(String::as_str) != Some("~") {
+                    self.start_row(&mut w, &rect, y)?;
+                    queue!(w, Print("~"))?;
+                    self.end_row(&mut w, &rect, 1)?;
+                    self.prev_lines.insert(key, "~".to_string());
                 }
                 continue;
             }
-            let line = ed.buf.line_string(file_row);
+            let line = buf.line_string(file_row);
             let mut col = 0usize;
-            let start_col = self.off_x;
-            let end_col = start_col + self.screen_cols as usize;
-            if matches!(selection, Sel::None) {
+            let start_col = view.left;
+            let end_col = start_col + rect.width;
+            let matches = match &highlight {
+                Some(re) => buf.regex_line_matches(re, file_row),
+                None => Vec::new(),
+            };
+            let current = current
+                .filter(|&(y, _, _)| y == file_row)
+                .map(|(_, s, e)| (s, e));
+            let bracket_cols: Vec<usize> = brackets
+                .iter()
+                .flatten()
+                .filter(|&&(y, _)| y == file_row)
+                .map(|&(_, x)| x)
+                .collect();
+            if matches!(selection, Sel::None)
+                && matches.is_empty()
+                && current.is_none()
+                && bracket_cols.is_empty()
+            {
                 // Fast path: no selection; build string and cache
                 let mut out = String::new();
+                let mut used = 0;
                 for g in line.graphemes(true) {
                     let gw = gw_at(col, g);
                     let next = col + gw;
@@ -179,31 +261,24 @@ impl Ui {
                         col = next;
                         continue;
                     }
-                    if col >= end_col {
+                    if next > end_col {
                         break;
                     }
-                    if g == "\t" {
-                        out.push_str(&" ".repeat(gw));
-                    } else {
-                        out.push_str(g);
-                    }
+                    out.push_str(&Self::render_grapheme(g, gw));
+                    used += gw;
                     col = next;
-                    if col >= end_col {
-                        break;
-                    }
                 }
-                if self.prev_lines[row] != out {
-                    queue!(
-                        w,
-                        MoveTo(0, row as u16),
-                        Clear(ClearType::CurrentLine),
-                        Print(&out)
-                    )?;
-                    self.prev_lines[row] = out;
+                if self.prev_lines.get(&key) != Some(&out) {
+                    self.start_row(&mut w, &rect, y)?;
+                    queue!(w, Print(&out))?;
+                    self.end_row(&mut w, &rect, used)?;
+                    self.prev_lines.insert(key, out);
                 }
             } else {
-                // Visual mode: render with background on selection; no caching
-                queue!(w, MoveTo(0, row as u16), Clear(ClearType::CurrentLine))?;
+                // Selection, search matches or a bracket pair: render with backgrounds; no caching.
+                // NUL never appears in rendered text, so the next cached draw repaints.
+                self.start_row(&mut w, &rect, y)?;
+                self.prev_lines.insert(key, "\0".to_string());
                 let (sel_start, sel_end) = match selection {
                     Sel::Char { sy, sx, ey, ex } => {
                         if file_row < sy || file_row > ey {
@@ -211,18 +286,18 @@ impl Ui {
                         } else if sy == ey {
                             (sx, ex)
                         } else if file_row == sy {
-                            (sx, ed.buf.line_width(file_row))
+                            (sx, buf.line_width(file_row))
                         } else if file_row == ey {
                             (0, ex)
                         } else {
-                            (0, ed.buf.line_width(file_row))
+                            (0, buf.line_width(file_row))
                         }
                     }
                     Sel::Line { sy, ey } => {
                         if file_row < sy || file_row > ey {
                             (usize::MAX, usize::MAX)
                         } else {
-                            (0, ed.buf.line_width(file_row))
+                            (0, buf.line_width(file_row))
                         }
                     }
                     Sel::Block {
@@ -239,6 +314,7 @@ impl Ui {
                     }
                     Sel::None => (usize::MAX, usize::MAX),
                 };
+                let mut used = 0;
                 for g in line.graphemes(true) {
                     let gw = gw_at(col, g);
                     let next = col + gw;
@@ -246,66 +322,103 @@ impl Ui {
                         col = next;
                         continue;
                     }
-                    if col >= end_col {
+                    if next > end_col {
                         break;
                     }
                     let overlapped = sel_start != usize::MAX && (col < sel_end && next > sel_start);
-                    if overlapped {
+                    let matched = matches.iter().any(|&(s, e)| col < e && next > s);
+                    let is_current = current.is_some_and(|(s, e)| col < e && next > s);
+                    let is_bracket = bracket_cols.contains(&col);
+                    if is_current {
+                        queue!(
+                            w,
+                            SetBackgroundColor(Color::Magenta),
+                            SetForegroundColor(Color::White)
+                        )?;
+                    } else if overlapped {
                         queue!(
                             w,
                             SetBackgroundColor(Color::DarkGrey),
                             SetForegroundColor(Color::White)
                         )?;
+                    } else if matched {
+                        queue!(
+                            w,
+                            SetBackgroundColor(Color::Yellow),
+                            SetForegroundColor(Color::Black)
+                        )?;
+                    } else if is_bracket {
+                        queue!(
+                            w,
+                            SetBackgroundColor(Color::Cyan),
+                            SetForegroundColor(Color::Black)
+                        )?;
                     }
-                    // Render tabs as spaces according to tabstop
-                    if g == "\t" {
-                        queue!(w, Print(" ".repeat(gw)))?;
-                    } else {
-                        queue!(w, Print(g))?;
-                    }
-                    if overlapped {
+                    queue!(w, Print(Self::render_grapheme(g, gw)))?;
+                    if overlapped || matched || is_current || is_bracket {
                         queue!(
                             w,
                             SetForegroundColor(Color::Reset),
                             SetBackgroundColor(Color::Reset)
                         )?;
                     }
+                    used += gw;
                     col = next;
-                    if col >= end_col {
-                        break;
-                    }
                 }
-                // No change to prev_lines in visual mode (we always redraw)
+                self.end_row(&mut w, &rect, used)?;
             }
         }
         Ok(())
     }
 
-    fn draw_status_bar<W: Write>(&mut self, mut w: W, ed: &Editor) -> io::Result<()> {
-        let status_row = self.screen_rows;
-        let fname = ed
+    /// The bottom row of each window: file name, line count and position,
+    /// and the mode in the current window, which is drawn brighter.
+    fn draw_status_bar<W: Write>(
+        &mut self,
+        mut w: W,
+        ed: &Editor,
+        view: &WindowView,
+    ) -> io::Result<()> {
+        let rect = view.rect;
+        let status_row = rect.y + rect.height.saturating_sub(1);
+        let fname = view
             .filename
-            .as_ref()
             .and_then(|p| p.file_name())
             .and_then(|s| s.to_str())
             .unwrap_or("[No Name]");
-        let dirty = if ed.dirty { " +" } else { "" };
+        let dirty = if view.dirty { " +" } else { "" };
         let mode = match ed.mode {
             crate::keymap::Mode::Normal => "NORMAL",
             crate::keymap::Mode::Insert => "INSERT",
+            crate::keymap::Mode::Replace => "REPLACE",
             crate::keymap::Mode::Visual => "VISUAL",
             crate::keymap::Mode::VisualLine => "VISUAL-LINE",
             crate::keymap::Mode::VisualBlock => "VISUAL-BLOCK",
         };
-        let left_full = format!(
-            " {}{} — {} lines [{}] ",
-            fname,
-            dirty,
-            ed.buf.line_count(),
-            mode
+        let recording = match ed.recording() {
+            Some(name) => format!("recording @{} ", name),
+            None => String::new(),
+        };
+        let left_full = if view.current {
+            format!(
+                " {}{} — {} lines [{}] {}",
+                fname,
+                dirty,
+                view.buf.line_count(),
+                mode,
+                recording
+            )
+        } else {
+            format!(" {}{} — {} lines", fname, dirty, view.buf.line_count())
+        };
+        let right_full = format!(
+            " {} {} {}/{} ",
+            view.encoding.name(),
+            view.line_ending.name(),
+            view.cursor.0 + 1,
+            view.buf.line_count()
         );
-        let right_full = format!(" {}/{} ", ed.cy + 1, ed.buf.line_count());
-        let total = self.screen_cols as usize;
+        let total = rect.width;
         // Compute widths
         let right_w = UnicodeWidthStr::width(right_full.as_str());
         // Space available for left side including padding
@@ -329,27 +442,136 @@ impl Ui {
         if right_w <= total.saturating_sub(left_w + pad) {
             content.push_str(&right_full);
         }
-        if self.prev_status != content {
+        let content_w = UnicodeWidthStr::width(content.as_str());
+        content.push_str(&" ".repeat(total.saturating_sub(content_w)));
+        let key = (rect.x, status_row);
+        let drawn = (view.current, content);
+        if self.prev_status.get(&key) != Some(&drawn) {
+            let (fg, bg) = if view.current {
+                (Color::Black, Color::White)
+            } else {
+                (Color::White, Color::DarkGrey)
+            };
+            queue!(
+                w,
+                MoveTo(rect.x as u16, status_row as u16),
+                SetForegroundColor(fg),
+                SetBackgroundColor(bg),
+                Print(&drawn.1),
+                SetForegroundColor(Color::Reset),
+                SetBackgroundColor(Color::Reset)
+            )?;
+            self.prev_status.insert(key, drawn);
+        }
+        Ok(())
+    }
+
+    /// The column between side-by-side windows, drawn right of every window
+    /// that doesn't reach the edge of the screen.
+    fn draw_separators<W: Write>(&self, mut w: W, views: &[WindowView]) -> io::Result<()> {
+        for view in views {
+            let rect = view.rect;
+            let x = rect.x + rect.width;
+            if x >= self.screen_cols as usize {
+                continue;
+            }
+            queue!(
+                w,
+                SetForegroundColor(Color::White),
+                SetBackgroundColor(Color::DarkGrey)
+            )?;
+            for y in rect.y..rect.y + rect.height {
+                queue!(w, MoveTo(x as u16, y as u16), Print("|"))?;
+            }
             queue!(
                 w,
-                MoveTo(0, status_row),
-                Clear(ClearType::CurrentLine),
-                SetForegroundColor(Color::Black),
-                SetBackgroundColor(Color::White),
-                Print(&content),
                 SetForegroundColor(Color::Reset),
                 SetBackgroundColor(Color::Reset)
             )?;
-            self.prev_status = content;
         }
         Ok(())
     }
 
+    /// The top row when there are tab pages: a label for each, the current
+    /// one drawn brighter. When they don't all fit, each gets an equal share
+    /// of the width, cut like the status line.
+    fn draw_tab_line<W: Write>(&mut self, mut w: W, ed: &Editor) -> io::Result<()> {
+        if ed.tab_count() == 1 {
+            return Ok(());
+        }
+        let labels = ed.tab_labels();
+        if self.prev_tabs == labels {
+            return Ok(());
+        }
+        let cols = self.screen_cols as usize;
+        let total: usize = labels
+            .iter()
+            .map(|(l, _)| UnicodeWidthStr::width(l.as_str()))
+            .sum();
+        let share = if total > cols {
+            (cols / labels.len()).max(1)
+        } else {
+            cols
+        };
+        queue!(w, MoveTo(0, 0))?;
+        let mut used = 0;
+        for (label, current) in &labels {
+            let text = Self::truncate_to_width(label, share.min(cols - used));
+            let (fg, bg) = if *current {
+                (Color::Black, Color::White)
+            } else {
+                (Color::White, Color::DarkGrey)
+            };
+            queue!(
+                w,
+                SetForegroundColor(fg),
+                SetBackgroundColor(bg),
+                Print(&text)
+            )?;
+            used += UnicodeWidthStr::width(text.as_str());
+        }
+        queue!(
+            w,
+            SetForegroundColor(Color::White),
+            SetBackgroundColor(Color::DarkGrey),
+            Print(" ".repeat(cols - used)),
+            SetForegroundColor(Color::Reset),
+            SetBackgroundColor(Color::Reset)
+        )?;
+        self.prev_tabs = labels;
+        Ok(())
+    }
+
     fn draw_message_bar<W: Write>(&mut self, mut w: W, ed: &Editor) -> io::Result<()> {
-        if ed.status.is_empty() {
+        // A pending question stays up regardless of the status timeout, and is
+        // redrawn every time since the status bar shares its row
+        if let Some(prompt) = ed.substitute_prompt() {
+            let msg = Self::truncate_to_width(&prompt, self.screen_cols as usize);
+            queue!(
+                w,
+                MoveTo(0, self.screen_rows),
+                Clear(ClearType::CurrentLine),
+                SetForegroundColor(Color::Green),
+                Print(&msg),
+                SetForegroundColor(Color::Reset)
+            )?;
+            self.prev_status.clear();
+            self.prev_message = msg;
             return Ok(());
         }
-        if ed.status_time.elapsed() > Duration::from_millis(STATUS_TIMEOUT_MS) {
+        let expired = ed.status.is_empty()
+            || ed.status_time.elapsed() > Duration::from_millis(STATUS_TIMEOUT_MS);
+        if expired {
+            // With split windows the message row is nobody's status line,
+            // so an old message has to be wiped
+            if ed.windows.len() > 1 && !self.prev_message.is_empty() {
+                queue!(
+                    w,
+                    MoveTo(0, self.screen_rows),
+                    Clear(ClearType::CurrentLine)
+                )?;
+                self.prev_message.clear();
+            }
             return Ok(());
         }
         let msg = Self::truncate_to_width(&ed.status, self.screen_cols as usize);
@@ -369,21 +591,39 @@ impl Ui {
 
     pub fn refresh<W: Write>(&mut self, mut w: W, ed: &mut Editor) -> io::Result<()> {
         ed.clamp_cursor();
+        ed.layout_windows(self.window_area(ed));
         self.scroll(ed);
-        self.draw_rows(&mut w, ed)?;
-        self.draw_status_bar(&mut w, ed)?;
+        let views = ed.window_views();
+        let rects: Vec<Rect> = views.iter().map(|v| v.rect).collect();
+        if rects != self.prev_rects {
+            queue!(w, Clear(ClearType::All))?;
+            self.clear_cache();
+            self.prev_rects = rects;
+        }
+        self.draw_tab_line(&mut w, ed)?;
+        for view in &views {
+            self.draw_rows(&mut w, ed, view)?;
+            self.draw_status_bar(&mut w, ed, view)?;
+        }
+        self.draw_separators(&mut w, &views)?;
         self.draw_message_bar(&mut w, ed)?;
-        let cur_y = (ed.cy - self.off_y) as u16;
-        let cur_x = (ed.cx.saturating_sub(self.off_x)) as u16;
+        let win = ed.windows.current();
+        let cur_y = (win.rect.y + ed.cy - ed.viewport.top) as u16;
+        let cur_x = (win.rect.x + ed.cx.saturating_sub(win.left)) as u16;
         queue!(w, MoveTo(cur_x, cur_y), Show)?;
+        if let Some(seq) = ed.system_clipboard.take_osc52() {
+            queue!(w, Print(seq))?;
+        }
         w.flush()?;
         Ok(())
     }
 
     pub fn clear_cache(&mut self) {
-        self.prev_lines.fill(String::new());
+        self.prev_lines.clear();
         self.prev_status.clear();
-        self.prev_message.clear();
+        self.prev_tabs.clear();
+        // Unknown: the message row is redrawn or wiped
+        self.prev_message = "\0".to_string();
     }
 
     pub fn time_until_status_expiry(&self, ed: &Editor) -> Option<Duration> {
@@ -400,6 +640,56 @@ impl Ui {
         }
     }
 
+    /// Shows `ed.message_lines` over the bottom of the screen, one page at a
+    /// time, waiting for a key after each page. `q` or Esc stops early.
+    pub fn show_message_lines<W: Write>(&mut self, ed: &mut Editor, mut w: W) -> io::Result<()> {
+        let lines = std::mem::take(&mut ed.message_lines);
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let page = (self.screen_rows as usize).max(1);
+        let cols = self.screen_cols as usize;
+        let mut chunks = lines.chunks(page).peekable();
+        while let Some(chunk) = chunks.next() {
+            let first_row = page - chunk.len();
+            for (i, line) in chunk.iter().enumerate() {
+                queue!(
+                    w,
+                    MoveTo(0, (first_row + i) as u16),
+                    Clear(ClearType::CurrentLine),
+                    Print(Self::truncate_to_width(line, cols))
+                )?;
+            }
+            let more = chunks.peek().is_some();
+            let prompt = if more {
+                "-- More --"
+            } else {
+                "Press ENTER or type command to continue"
+            };
+            queue!(
+                w,
+                MoveTo(0, self.screen_rows),
+                Clear(ClearType::CurrentLine),
+                SetForegroundColor(Color::Green),
+                Print(Self::truncate_to_width(prompt, cols)),
+                SetForegroundColor(Color::Reset)
+            )?;
+            w.flush()?;
+            let key = loop {
+                match input::next_event(ed, Duration::from_millis(250))? {
+                    Some(Event::Key(KeyEvent { code, .. })) => break code,
+                    Some(Event::Resize(wid, hgt)) => self.resize(wid, hgt),
+                    _ => {}
+                }
+            };
+            if matches!(key, KeyCode::Esc | KeyCode::Char('q')) {
+                break;
+            }
+        }
+        self.clear_cache();
+        Ok(())
+    }
+
     pub fn prompt_filename<W: Write>(
         &mut self,
         ed: &mut Editor,
@@ -418,24 +708,20 @@ impl Ui {
                 Print(shown.clone())
             )?;
             w.flush()?;
-            if poll(Duration::from_millis(250))? {
-                match read()? {
+            if let Some(event) = input::next_event(ed, Duration::from_millis(250))? {
+                match event {
                     Event::Key(KeyEvent {
                         code, modifiers, ..
                     }) => match (code, modifiers) {
                         (KeyCode::Esc, _) => return Ok(None),
-                        (KeyCode::Enter, _) => {
-                            if !input.is_empty() {
-                                return Ok(Some(input));
-                            }
-                        }
+                        (KeyCode::Enter, _) if !input.is_empty() => return Ok(Some(input)),
                         (KeyCode::Backspace, _) | (KeyCode::Delete, _) => {
                             input.pop();
                         }
-                        (KeyCode::Char(c), m) => {
-                            if !m.contains(KeyModifiers::CONTROL) && !c.is_control() {
-                                input.push(c);
-                            }
+                        (KeyCode::Char(c), m)
+                            if !m.contains(KeyModifiers::CONTROL) && !c.is_control() =>
+                        {
+                            input.push(c);
                         }
                         _ => {}
                     },
@@ -449,15 +735,50 @@ impl Ui {
     }
 
     pub fn prompt_command<W: Write>(
+        &mut self,
+        ed: &mut Editor,
+        w: W,
+        initial: &str,
+    ) -> io::Result<Option<String>> {
+        self.prompt_line(ed, w, ':', initial, |_, _| {})
+    }
+
+    /// Reads a search pattern after `/` or `?`, moving the cursor to the
+    /// first match as the pattern is typed. Esc returns to the start.
+    pub fn prompt_search<W: Write>(
+        &mut self,
+        ed: &mut Editor,
+        w: W,
+        backward: bool,
+    ) -> io::Result<()> {
+        let origin = (ed.cx, ed.cy);
+        let prefix = if backward { '?' } else { '/' };
+        let input = self.prompt_line(ed, w, prefix, "", |ed, input| {
+            ed.search_preview(input, backward, origin)
+        })?;
+        match input {
+            Some(pat) => ed.search_commit(&pat, backward, origin),
+            None => ed.search_cancel(origin),
+        }
+        Ok(())
+    }
+
+    /// Line editor on the message row, starting with `initial` typed in.
+    /// `on_change` runs after every edit of the input so callers can update
+    /// the editor live.
+    fn prompt_line<W: Write>(
         &mut self,
         ed: &mut Editor,
         mut w: W,
+        prefix: char,
+        initial: &str,
+        mut on_change: impl FnMut(&mut Editor, &str),
     ) -> io::Result<Option<String>> {
-        let mut input = String::new();
+        let mut input = initial.to_string();
         loop {
             self.refresh(&mut w, ed)?;
             let display =
-                Self::truncate_to_width(&format!(":{}", input), self.screen_cols as usize);
+                Self::truncate_to_width(&format!("{}{}", prefix, input), self.screen_cols as usize);
             queue!(
                 w,
                 MoveTo(0, self.screen_rows),
@@ -465,8 +786,8 @@ impl Ui {
                 Print(display)
             )?;
             w.flush()?;
-            if poll(Duration::from_millis(250))? {
-                match read()? {
+            if let Some(event) = input::next_event(ed, Duration::from_millis(250))? {
+                match event {
                     Event::Key(KeyEvent {
                         code, modifiers, ..
                     }) => match (code, modifiers) {
@@ -474,11 +795,13 @@ impl Ui {
                         (KeyCode::Enter, _) => return Ok(Some(input)),
                         (KeyCode::Backspace, _) | (KeyCode::Delete, _) => {
                             input.pop();
+                            on_change(ed, &input);
                         }
-                        (KeyCode::Char(c), m) => {
-                            if !m.contains(KeyModifiers::CONTROL) && !c.is_control() {
-                                input.push(c);
-                            }
+                        (KeyCode::Char(c), m)
+                            if !m.contains(KeyModifiers::CONTROL) && !c.is_control() =>
+                        {
+                            input.push(c);
+                            on_change(ed, &input);
                         }
                         _ => {}
                     },
@@ -0,0 +1,651 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::buffer::{Buffer, EditOp};
+
+/// Default number of undo steps kept (Vim's `undolevels`).
+pub const DEFAULT_UNDO_LEVELS: usize = 1000;
+
+/// One undoable step: the rope edits it made plus the cursor around it.
+/// Cursor positions are `(cx, cy)`.
+struct UndoNode {
+    parent: usize,
+    /// Children in creation order; `cur_child` is the branch redo follows.
+    children: Vec<usize>,
+    cur_child: Option<usize>,
+    time: SystemTime,
+    ops: Vec<EditOp>,
+    cursor_before: (usize, usize),
+    cursor_after: (usize, usize),
+}
+
+/// A leaf of the tree as listed by `:undolist`.
+pub struct UndoLeaf {
+    pub seq: usize,
+    pub changes: usize,
+    pub time: SystemTime,
+}
+
+/// Undo history kept as a tree so that no branch is lost when editing after
+/// an undo. Nodes are keyed by their change number (`seq`), which orders
+/// them chronologically; the root is the oldest state still reachable.
+pub struct UndoTree {
+    nodes: BTreeMap<usize, UndoNode>,
+    root: usize,
+    current: usize,
+    last_seq: usize,
+    limit: usize,
+}
+
+impl Default for UndoTree {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(0, UndoNode::new(0, SystemTime::now(), (0, 0)));
+        Self {
+            nodes,
+            root: 0,
+            current: 0,
+            last_seq: 0,
+            limit: DEFAULT_UNDO_LEVELS,
+        }
+    }
+}
+
+impl UndoNode {
+    fn new(parent: usize, time: SystemTime, cursor: (usize, usize)) -> Self {
+        Self {
+            parent,
+            children: Vec::new(),
+            cur_child: None,
+            time,
+            ops: Vec::new(),
+            cursor_before: cursor,
+            cursor_after: cursor,
+        }
+    }
+}
+
+impl UndoTree {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.depth(self.current)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Change number of the state the buffer is in (0 = original text).
+    pub fn current_seq(&self) -> usize {
+        self.current
+    }
+
+    /// Sets the maximum number of steps kept; the oldest are dropped first.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    fn node(&self, seq: usize) -> &UndoNode {
+        &self.nodes[&seq]
+    }
+
+    fn node_mut(&mut self, seq: usize) -> &mut UndoNode {
+        self.nodes.get_mut(&seq).expect("undo node")
+    }
+
+    /// Number of changes between the root and `seq`.
+    fn depth(&self, mut seq: usize) -> usize {
+        let mut d = 0;
+        while seq != self.root {
+            seq = self.node(seq).parent;
+            d += 1;
+        }
+        d
+    }
+
+    /// Opens a new step below the current state. Edits recorded afterwards belong to it.
+    pub fn begin(&mut self, cursor: (usize, usize)) {
+        self.begin_at(cursor, SystemTime::now());
+    }
+
+    fn begin_at(&mut self, cursor: (usize, usize), time: SystemTime) {
+        self.last_seq += 1;
+        let seq = self.last_seq;
+        let parent = self.current;
+        self.nodes.insert(seq, UndoNode::new(parent, time, cursor));
+        let p = self.node_mut(parent);
+        p.children.push(seq);
+        p.cur_child = Some(seq);
+        self.current = seq;
+        self.trim();
+    }
+
+    /// Appends edits to the current step.
+    pub fn record(&mut self, ops: Vec<EditOp>) {
+        if ops.is_empty() || self.current == self.root {
+            return;
+        }
+        let cur = self.current;
+        self.node_mut(cur).ops.extend(ops);
+    }
+
+    /// Reverts the current step, moving to its parent. Returns the cursor to restore.
+    pub fn undo(&mut self, buf: &mut Buffer, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        if self.current == self.root {
+            return None;
+        }
+        let seq = self.current;
+        let node = self.node_mut(seq);
+        for op in node.ops.iter().rev() {
+            buf.apply(&op.inverse());
+        }
+        node.cursor_after = cursor;
+        let (parent, restore) = (node.parent, node.cursor_before);
+        self.node_mut(parent).cur_child = Some(seq);
+        self.current = parent;
+        Some(restore)
+    }
+
+    /// Re-applies the most recently visited child step. Returns the cursor to restore.
+    pub fn redo(&mut self, buf: &mut Buffer) -> Option<(usize, usize)> {
+        let child = self.node(self.current).cur_child?;
+        self.apply_child(buf, child);
+        Some(self.node(child).cursor_after)
+    }
+
+    fn apply_child(&mut self, buf: &mut Buffer, child: usize) {
+        for op in &self.node(child).ops {
+            buf.apply(op);
+        }
+        let parent = self.node(child).parent;
+        self.node_mut(parent).cur_child = Some(child);
+        self.current = child;
+    }
+
+    /// Moves the buffer to the state after change `target`, undoing up to the
+    /// common ancestor and redoing down the target's branch.
+    pub fn goto(
+        &mut self,
+        buf: &mut Buffer,
+        target: usize,
+        cursor: (usize, usize),
+    ) -> Option<(usize, usize)> {
+        if !self.nodes.contains_key(&target) || target == self.current {
+            return None;
+        }
+        let mut down = Vec::new();
+        let mut t = target;
+        while !self.is_ancestor(t, self.current) {
+            down.push(t);
+            t = self.node(t).parent;
+        }
+        let mut restore = cursor;
+        while self.current != t {
+            restore = self.undo(buf, restore)?;
+        }
+        for &seq in down.iter().rev() {
+            self.apply_child(buf, seq);
+            restore = self.node(seq).cursor_after;
+        }
+        Some(restore)
+    }
+
+    fn is_ancestor(&self, anc: usize, mut seq: usize) -> bool {
+        loop {
+            if seq == anc {
+                return true;
+            }
+            if seq == self.root {
+                return false;
+            }
+            seq = self.node(seq).parent;
+        }
+    }
+
+    /// Change number `steps` states earlier in time (`g-`), clamped at the root.
+    pub fn earlier_by_steps(&self, steps: usize) -> usize {
+        self.nodes
+            .range(..self.current)
+            .rev()
+            .nth(steps.saturating_sub(1))
+            .map(|(&seq, _)| seq)
+            .unwrap_or(self.root)
+    }
+
+    /// Change number `steps` states later in time (`g+`), clamped at the newest.
+    pub fn later_by_steps(&self, steps: usize) -> usize {
+        self.nodes
+            .range(self.current + 1..)
+            .take(steps.max(1))
+            .last()
+            .map(|(&seq, _)| seq)
+            .unwrap_or(self.current)
+    }
+
+    /// Newest state made at least `d` before the current state (`:earlier 5m`).
+    pub fn earlier_by_time(&self, d: Duration) -> usize {
+        let now = self.node(self.current).time;
+        let cutoff = now.checked_sub(d).unwrap_or(SystemTime::UNIX_EPOCH);
+        self.nodes
+            .range(..self.current)
+            .rev()
+            .find(|(_, n)| n.time <= cutoff)
+            .map(|(&seq, _)| seq)
+            .unwrap_or(self.root)
+    }
+
+    /// Newest state made no later than `d` after the current state (`:later 5m`).
+    pub fn later_by_time(&self, d: Duration) -> usize {
+        let now = self.node(self.current).time;
+        let cutoff = now + d;
+        self.nodes
+            .range(self.current + 1..)
+            .take_while(|(_, n)| n.time <= cutoff)
+            .last()
+            .map(|(&seq, _)| seq)
+            .unwrap_or(self.current)
+    }
+
+    /// Leaves of every branch, oldest first.
+    pub fn leaves(&self) -> Vec<UndoLeaf> {
+        self.nodes
+            .iter()
+            .filter(|(&seq, n)| seq != self.root && n.children.is_empty())
+            .map(|(&seq, n)| UndoLeaf {
+                seq,
+                changes: self.depth(seq),
+                time: n.time,
+            })
+            .collect()
+    }
+
+    /// Drops the oldest steps while more than `limit` are kept. The root's
+    /// child on the path to the current state becomes the new root; sibling
+    /// branches of the old root can no longer be reached and are removed.
+    fn trim(&mut self) {
+        while self.nodes.len() - 1 > self.limit {
+            let old_root = self.root;
+            let keep = if self.current == old_root {
+                None
+            } else {
+                let mut s = self.current;
+                while self.node(s).parent != old_root {
+                    s = self.node(s).parent;
+                }
+                Some(s)
+            };
+            let children = self.node(old_root).children.clone();
+            for c in children {
+                if Some(c) != keep {
+                    self.remove_subtree(c);
+                }
+            }
+            match keep {
+                Some(k) => {
+                    self.nodes.remove(&old_root);
+                    let n = self.node_mut(k);
+                    n.ops.clear();
+                    self.root = k;
+                }
+                None => {
+                    let r = self.node_mut(old_root);
+                    r.children.clear();
+                    r.cur_child = None;
+                }
+            }
+        }
+    }
+
+    /// Serializes the tree for an undo file, tagged with the hash of the text
+    /// it applies to. All integers are little-endian `u64`.
+    pub fn write_to<W: Write>(&self, w: &mut W, content_hash: u64) -> io::Result<()> {
+        w.write_all(UNDOFILE_MAGIC)?;
+        put(w, content_hash)?;
+        for v in [self.root, self.current, self.last_seq, self.nodes.len()] {
+            put(w, v as u64)?;
+        }
+        for (&seq, n) in &self.nodes {
+            let since_epoch = n
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            for v in [
+                seq,
+                n.parent,
+                n.cur_child.map(|c| c + 1).unwrap_or(0),
+                since_epoch.as_secs() as usize,
+                n.cursor_before.0,
+                n.cursor_before.1,
+                n.cursor_after.0,
+                n.cursor_after.1,
+                n.children.len(),
+            ] {
+                put(w, v as u64)?;
+            }
+            for &c in &n.children {
+                put(w, c as u64)?;
+            }
+            put(w, n.ops.len() as u64)?;
+            for op in &n.ops {
+                let (kind, at, text) = match op {
+                    EditOp::Insert { at, text } => (0, at, text),
+                    EditOp::Remove { at, text } => (1, at, text),
+                };
+                put(w, kind)?;
+                put(w, *at as u64)?;
+                put(w, text.len() as u64)?;
+                w.write_all(text.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a tree written by `write_to`. Returns `Ok(None)` when the file
+    /// was written for different text than `content_hash`.
+    pub fn read_from<R: Read>(r: &mut R, content_hash: u64) -> io::Result<Option<Self>> {
+        let mut magic = [0u8; UNDOFILE_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != *UNDOFILE_MAGIC {
+            return Err(invalid("not a rune undo file"));
+        }
+        if get(r)? != content_hash {
+            return Ok(None);
+        }
+        let root = get_usize(r)?;
+        let current = get_usize(r)?;
+        let last_seq = get_usize(r)?;
+        let count = get_usize(r)?;
+        let mut nodes = BTreeMap::new();
+        for _ in 0..count {
+            let seq = get_usize(r)?;
+            let parent = get_usize(r)?;
+            let cur_child = get_usize(r)?.checked_sub(1);
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(get(r)?);
+            let cursor_before = (get_usize(r)?, get_usize(r)?);
+            let cursor_after = (get_usize(r)?, get_usize(r)?);
+            let n_children = get_usize(r)?;
+            let children = (0..n_children)
+                .map(|_| get_usize(r))
+                .collect::<io::Result<Vec<_>>>()?;
+            let n_ops = get_usize(r)?;
+            let mut ops = Vec::with_capacity(n_ops.min(1024));
+            for _ in 0..n_ops {
+                let kind = get(r)?;
+                let at = get_usize(r)?;
+                let len = get_usize(r)?;
+                let mut bytes = Vec::new();
+                r.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+                if bytes.len() != len {
+                    return Err(invalid("truncated undo file"));
+                }
+                let text = String::from_utf8(bytes).map_err(|_| invalid("bad text"))?;
+                ops.push(match kind {
+                    0 => EditOp::Insert { at, text },
+                    _ => EditOp::Remove { at, text },
+                });
+            }
+            nodes.insert(
+                seq,
+                UndoNode {
+                    parent,
+                    children,
+                    cur_child,
+                    time,
+                    ops,
+                    cursor_before,
+                    cursor_after,
+                },
+            );
+        }
+        if !Self::consistent(&nodes, root, current, last_seq) {
+            return Err(invalid("corrupt undo file"));
+        }
+        Ok(Some(Self {
+            nodes,
+            root,
+            current,
+            last_seq,
+            limit: DEFAULT_UNDO_LEVELS,
+        }))
+    }
+
+    /// Whether nodes read from an undo file form one tree under `root`:
+    /// every link points at a node that links back, and going down from the
+    /// root reaches each node exactly once, so parent chains end there.
+    fn consistent(
+        nodes: &BTreeMap<usize, UndoNode>,
+        root: usize,
+        current: usize,
+        last_seq: usize,
+    ) -> bool {
+        if !nodes.contains_key(&root) || !nodes.contains_key(&current) {
+            return false;
+        }
+        let linked = nodes.iter().all(|(&seq, n)| {
+            let parent_ok = seq == root
+                || nodes
+                    .get(&n.parent)
+                    .is_some_and(|p| p.children.contains(&seq));
+            let children_ok = n
+                .children
+                .iter()
+                .all(|c| nodes.get(c).is_some_and(|child| child.parent == seq));
+            let cur_ok = n.cur_child.is_none_or(|c| n.children.contains(&c));
+            seq <= last_seq && parent_ok && children_ok && cur_ok
+        });
+        if !linked {
+            return false;
+        }
+        let mut seen = 0;
+        let mut stack = vec![root];
+        while let Some(seq) = stack.pop() {
+            seen += 1;
+            if seen > nodes.len() {
+                // A cycle, or a node listed twice
+                return false;
+            }
+            stack.extend(&nodes[&seq].children);
+        }
+        seen == nodes.len()
+    }
+
+    fn remove_subtree(&mut self, seq: usize) {
+        if let Some(n) = self.nodes.remove(&seq) {
+            for c in n.children {
+                self.remove_subtree(c);
+            }
+        }
+    }
+}
+
+const UNDOFILE_MAGIC: &[u8; 10] = b"RUNEUNDO1\n";
+
+/// Undo file for `file` in directory `dir`, named after its absolute path
+/// with separators replaced by `%` (as Vim's `undodir` does).
+pub fn undofile_path(dir: &Path, file: &Path) -> Option<PathBuf> {
+    let abs = std::fs::canonicalize(file)
+        .or_else(|_| std::env::current_dir().map(|d| d.join(file)))
+        .ok()?;
+    let name: String = abs
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if matches!(c, '/' | '\\' | ':') {
+                '%'
+            } else {
+                c
+            }
+        })
+        .collect();
+    Some(dir.join(name))
+}
+
+fn put<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn get<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn get_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    usize::try_from(get(r)?).map_err(|_| invalid("value out of range"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(tree: &mut UndoTree, buf: &mut Buffer, text: &str, at: SystemTime) {
+        tree.begin_at((0, 0), at);
+        buf.insert_str_at(0, 0, text);
+        tree.record(buf.take_changes());
+    }
+
+    #[test]
+    fn limit_drops_oldest_steps() {
+        let mut buf = Buffer::from_string(String::new());
+        let mut h = UndoTree::default();
+        h.set_limit(2);
+        for (i, ch) in "abc".chars().enumerate() {
+            h.begin((i, 0));
+            buf.insert_char(i, 0, ch).unwrap();
+            h.record(buf.take_changes());
+        }
+        assert_eq!(h.len(), 2);
+        assert_eq!(h.undo(&mut buf, (3, 0)), Some((2, 0)));
+        assert_eq!(h.undo(&mut buf, (2, 0)), Some((1, 0)));
+        assert_eq!(h.undo(&mut buf, (1, 0)), None);
+        assert_eq!(buf.to_string(), "a");
+        assert_eq!(h.redo(&mut buf), Some((2, 0)));
+        assert_eq!(buf.to_string(), "ab");
+    }
+
+    #[test]
+    fn branches_survive_and_goto_crosses_them() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut buf = Buffer::from_string(String::new());
+        let mut tree = UndoTree::default();
+        edit(&mut tree, &mut buf, "a", t0);
+        edit(&mut tree, &mut buf, "b", t0);
+        tree.undo(&mut buf, (0, 0));
+        edit(&mut tree, &mut buf, "c", t0);
+        assert_eq!(buf.to_string(), "ca");
+        assert_eq!(tree.leaves().len(), 2);
+        // Chronologically the previous state is change 2 ("ba") on the other branch
+        let prev = tree.earlier_by_steps(1);
+        assert_eq!(prev, 2);
+        tree.goto(&mut buf, prev, (0, 0));
+        assert_eq!(buf.to_string(), "ba");
+        tree.goto(&mut buf, tree.later_by_steps(1), (0, 0));
+        assert_eq!(buf.to_string(), "ca");
+        tree.goto(&mut buf, 0, (0, 0));
+        assert_eq!(buf.to_string(), "");
+    }
+
+    #[test]
+    fn time_travel_by_duration() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut buf = Buffer::from_string(String::new());
+        let mut tree = UndoTree::default();
+        edit(&mut tree, &mut buf, "a", t0);
+        edit(&mut tree, &mut buf, "b", t0 + Duration::from_secs(60));
+        edit(&mut tree, &mut buf, "c", t0 + Duration::from_secs(400));
+        let seq = tree.earlier_by_time(Duration::from_secs(300));
+        tree.goto(&mut buf, seq, (0, 0));
+        assert_eq!(buf.to_string(), "ba");
+        let seq = tree.later_by_time(Duration::from_secs(30));
+        assert_eq!(seq, tree.current_seq());
+        let seq = tree.later_by_time(Duration::from_secs(600));
+        tree.goto(&mut buf, seq, (0, 0));
+        assert_eq!(buf.to_string(), "cba");
+    }
+
+    #[test]
+    fn serialized_tree_round_trips() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut buf = Buffer::from_string(String::new());
+        let mut tree = UndoTree::default();
+        edit(&mut tree, &mut buf, "a", t0);
+        edit(&mut tree, &mut buf, "b", t0);
+        tree.undo(&mut buf, (0, 0));
+        edit(&mut tree, &mut buf, "c", t0);
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes, 42).unwrap();
+
+        assert!(UndoTree::read_from(&mut bytes.as_slice(), 7)
+            .unwrap()
+            .is_none());
+        let mut loaded = UndoTree::read_from(&mut bytes.as_slice(), 42)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.current_seq(), 3);
+        assert_eq!(loaded.leaves().len(), 2);
+        loaded.goto(&mut buf, 2, (0, 0));
+        assert_eq!(buf.to_string(), "ba");
+        loaded.goto(&mut buf, 0, (0, 0));
+        assert_eq!(buf.to_string(), "");
+
+        assert!(UndoTree::read_from(&mut &bytes[..bytes.len() - 3], 42).is_err());
+    }
+
+    #[test]
+    fn corrupt_links_are_rejected() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let mut buf = Buffer::from_string(String::new());
+        let mut tree = UndoTree::default();
+        edit(&mut tree, &mut buf, "a", t0);
+        edit(&mut tree, &mut buf, "b", t0);
+        let corrupt: [fn(&mut UndoTree); 4] = [
+            |t| t.nodes.get_mut(&2).unwrap().parent = 5,
+            |t| t.nodes.get_mut(&1).unwrap().cur_child = Some(7),
+            // 1 and 2 as each other's parent, cut off from the root
+            |t| {
+                t.nodes.get_mut(&0).unwrap().children.clear();
+                t.nodes.get_mut(&1).unwrap().parent = 2;
+                t.nodes.get_mut(&2).unwrap().children.push(1);
+            },
+            |t| t.nodes.get_mut(&2).unwrap().children.push(2),
+        ];
+        let mut good = Vec::new();
+        tree.write_to(&mut good, 42).unwrap();
+        for f in corrupt {
+            let mut bad = UndoTree::read_from(&mut good.as_slice(), 42)
+                .unwrap()
+                .unwrap();
+            f(&mut bad);
+            let mut bytes = Vec::new();
+            bad.write_to(&mut bytes, 42).unwrap();
+            let err = UndoTree::read_from(&mut bytes.as_slice(), 42).err();
+            assert_eq!(
+                err.map(|e| e.to_string()).as_deref(),
+                Some("corrupt undo file")
+            );
+        }
+    }
+
+    #[test]
+    fn trimming_keeps_current_branch() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let mut buf = Buffer::from_string(String::new());
+        let mut tree = UndoTree::default();
+        edit(&mut tree, &mut buf, "a", t0);
+        tree.undo(&mut buf, (0, 0));
+        edit(&mut tree, &mut buf, "b", t0);
+        edit(&mut tree, &mut buf, "c", t0);
+        tree.set_limit(1);
+        assert_eq!(tree.leaves().len(), 1);
+        assert!(tree.undo(&mut buf, (0, 0)).is_some());
+        assert_eq!(buf.to_string(), "b");
+        assert!(tree.undo(&mut buf, (0, 0)).is_none());
+    }
+}
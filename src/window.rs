@@ -0,0 +1,508 @@
+//! Split windows: the layout tree that divides the screen between them.
+//!
+//! Each window takes a rectangle with its status line as the bottom row.
+//! Side-by-side windows have a one column separator between them. Sizes are
+//! kept in screen cells and fitted to the screen whenever it is laid out, so
+//! resizing the terminal keeps the proportions.
+
+/// Part of the screen, in cells.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Which way a split divides its space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    /// Windows stacked top to bottom (`:split`).
+    Rows,
+    /// Windows side by side (`:vsplit`).
+    Columns,
+}
+
+pub struct Window {
+    pub id: usize,
+    /// Number of the buffer shown. For the current window the editor's
+    /// `buffers.current` is authoritative; this is set when it stops being
+    /// current.
+    pub buffer: usize,
+    /// First display column shown.
+    pub left: usize,
+    /// Where the window is on the screen, status line included, as of the
+    /// last `layout`.
+    pub rect: Rect,
+}
+
+enum Node {
+    Leaf(usize),
+    /// Children with their size along the split: height including the
+    /// status line for `Rows`, width without the separator for `Columns`.
+    Split(Dir, Vec<(Node, usize)>),
+}
+
+pub struct Layout {
+    root: Node,
+    windows: Vec<Window>,
+    /// Id of the window with the cursor.
+    pub current: usize,
+    /// Screen area last laid out, laid out again after every change.
+    area: Rect,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+/// Smallest height of a window: one line of text and the status line.
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+
+impl Node {
+    fn contains(&self, id: usize) -> bool {
+        match self {
+            Node::Leaf(w) => *w == id,
+            Node::Split(_, children) => children.iter().any(|(c, _)| c.contains(id)),
+        }
+    }
+
+    /// Smallest size the node fits in along `dir`.
+    fn min_size(&self, dir: Dir) -> usize {
+        match self {
+            Node::Leaf(_) => match dir {
+                Dir::Rows => MIN_HEIGHT,
+                Dir::Columns => MIN_WIDTH,
+            },
+            Node::Split(d, children) => {
+                let mins = children.iter().map(|(c, _)| c.min_size(dir));
+                if *d != dir {
+                    mins.max().unwrap_or(0)
+                } else if dir == Dir::Columns {
+                    mins.sum::<usize>() + children.len() - 1
+                } else {
+                    mins.sum()
+                }
+            }
+        }
+    }
+
+    fn first_leaf(&self) -> usize {
+        match self {
+            Node::Leaf(id) => *id,
+            Node::Split(_, children) => children[0].0.first_leaf(),
+        }
+    }
+
+    fn leaves(&self, out: &mut Vec<usize>) {
+        match self {
+            Node::Leaf(id) => out.push(*id),
+            Node::Split(_, children) => children.iter().for_each(|(c, _)| c.leaves(out)),
+        }
+    }
+
+    /// Gives each window under the node its part of `rect`.
+    fn place(&mut self, rect: Rect, windows: &mut [Window]) {
+        match self {
+            Node::Leaf(id) => {
+                if let Some(w) = windows.iter_mut().find(|w| w.id == *id) {
+                    w.rect = rect;
+                }
+            }
+            Node::Split(dir, children) => {
+                let dir = *dir;
+                let (mut pos, total) = match dir {
+                    Dir::Rows => (rect.y, rect.height),
+                    Dir::Columns => (rect.x, (rect.width + 1).saturating_sub(children.len())),
+                };
+                let mins: Vec<usize> = children.iter().map(|(c, _)| c.min_size(dir)).collect();
+                let mut sizes: Vec<usize> = children.iter().map(|(_, s)| *s).collect();
+                fit(&mut sizes, &mins, total);
+                for ((child, size), new) in children.iter_mut().zip(sizes) {
+                    *size = new;
+                    let part = match dir {
+                        Dir::Rows => Rect {
+                            y: pos,
+                            height: new,
+                            ..rect
+                        },
+                        Dir::Columns => Rect {
+                            x: pos,
+                            width: new,
+                            ..rect
+                        },
+                    };
+                    child.place(part, windows);
+                    pos += new + usize::from(dir == Dir::Columns);
+                }
+            }
+        }
+    }
+
+    /// The split of direction `dir` closest above window `id`, and the
+    /// index of the child holding it.
+    fn split_of(&mut self, id: usize, dir: Dir) -> Option<(&mut Vec<(Node, usize)>, usize)> {
+        let Node::Split(d, children) = self else {
+            return None;
+        };
+        let i = children.iter().position(|(c, _)| c.contains(id))?;
+        if matches!(children[i].0, Node::Split(..)) && children[i].0.split_of(id, dir).is_some() {
+            return children[i].0.split_of(id, dir);
+        }
+        if *d == dir {
+            Some((children, i))
+        } else {
+            None
+        }
+    }
+
+    /// Removes window `id`, handing its space to a neighbour. Returns a
+    /// window of that neighbour.
+    fn remove(&mut self, id: usize) -> Option<usize> {
+        let Node::Split(_, children) = self else {
+            return None;
+        };
+        let i = children.iter().position(|(c, _)| c.contains(id))?;
+        if !matches!(children[i].0, Node::Leaf(_)) {
+            let next = children[i].0.remove(id);
+            // A split left with one child is replaced by it
+            if let Node::Split(_, inner) = &mut children[i].0 {
+                if inner.len() == 1 {
+                    let (only, _) = inner.pop().unwrap();
+                    children[i].0 = only;
+                }
+            }
+            return next;
+        }
+        let (_, size) = children.remove(i);
+        let to = if i > 0 { i - 1 } else { 0 };
+        children[to].1 += size;
+        Some(children[to].0.first_leaf())
+    }
+}
+
+/// Adjusts `sizes` to add up to `total`, keeping each at least its minimum
+/// where there is room: proportionally, then evening out from the end.
+fn fit(sizes: &mut [usize], mins: &[usize], total: usize) {
+    let sum: usize = sizes.iter().sum();
+    if sum == total {
+        return;
+    }
+    for (s, m) in sizes.iter_mut().zip(mins) {
+        *s = (*s * total).checked_div(sum).unwrap_or(0).max(*m);
+    }
+    let mut sum: usize = sizes.iter().sum();
+    if sum < total {
+        let last = sizes.len() - 1;
+        sizes[last] += total - sum;
+        sum = total;
+    }
+    for i in (0..sizes.len()).rev() {
+        if sum <= total {
+            break;
+        }
+        let cut = (sum - total).min(sizes[i].saturating_sub(mins[i]));
+        sizes[i] -= cut;
+        sum -= cut;
+    }
+    // Not even the minimums fit: shrink from the end regardless
+    for i in (0..sizes.len()).rev() {
+        if sum <= total {
+            break;
+        }
+        let cut = (sum - total).min(sizes[i]);
+        sizes[i] -= cut;
+        sum -= cut;
+    }
+}
+
+impl Layout {
+    /// A layout of one window, `id`, showing buffer `buffer`.
+    pub fn new(id: usize, buffer: usize) -> Self {
+        Self {
+            root: Node::Leaf(id),
+            windows: vec![Window {
+                id,
+                buffer,
+                left: 0,
+                rect: Rect::default(),
+            }],
+            current: id,
+            area: Rect::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Windows in layout order: top to bottom, left to right.
+    pub fn ids(&self) -> Vec<usize> {
+        let mut ids = Vec::new();
+        self.root.leaves(&mut ids);
+        ids
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Window> {
+        self.windows.iter()
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Window> {
+        self.windows.iter().find(|w| w.id == id)
+    }
+
+    pub fn current(&self) -> &Window {
+        self.get(self.current).expect("current window exists")
+    }
+
+    pub fn current_mut(&mut self) -> &mut Window {
+        let id = self.current;
+        self.windows
+            .iter_mut()
+            .find(|w| w.id == id)
+            .expect("current window exists")
+    }
+
+    /// Divides the screen area `area` between the windows.
+    pub fn layout(&mut self, area: Rect) {
+        self.area = area;
+        self.root.place(area, &mut self.windows);
+    }
+
+    pub fn area(&self) -> Rect {
+        self.area
+    }
+
+    fn relayout(&mut self) {
+        self.layout(self.area);
+    }
+
+    /// Splits the current window in two, the new half, window `id`, above
+    /// or to the left, showing the same buffer. False when the current one
+    /// is too small to split.
+    pub fn split(&mut self, dir: Dir, id: usize) -> bool {
+        let cur = self.current;
+        let rect = self.current().rect;
+        let (size, min) = match dir {
+            Dir::Rows => (rect.height, MIN_HEIGHT),
+            Dir::Columns => (rect.width, MIN_WIDTH),
+        };
+        // The separator takes a column
+        let room = size.saturating_sub(usize::from(dir == Dir::Columns));
+        if room < 2 * min {
+            return false;
+        }
+        let new_size = room - room / 2;
+        let old_size = room / 2;
+        let old = self.current();
+        self.windows.push(Window {
+            id,
+            buffer: old.buffer,
+            left: old.left,
+            rect: Rect::default(),
+        });
+        if let Some((children, i)) = self.root.split_of(cur, dir) {
+            if matches!(children[i].0, Node::Leaf(_)) {
+                children[i].1 = old_size;
+                children.insert(i, (Node::Leaf(id), new_size));
+                self.relayout();
+                return true;
+            }
+        }
+        let leaf = self.leaf_mut(cur).expect("current window is in the layout");
+        *leaf = Node::Split(
+            dir,
+            vec![(Node::Leaf(id), new_size), (Node::Leaf(cur), old_size)],
+        );
+        self.relayout();
+        true
+    }
+
+    fn leaf_mut(&mut self, id: usize) -> Option<&mut Node> {
+        fn find(node: &mut Node, id: usize) -> Option<&mut Node> {
+            match node {
+                Node::Leaf(w) if *w == id => Some(node),
+                Node::Leaf(_) => None,
+                Node::Split(_, children) => children.iter_mut().find_map(|(c, _)| find(c, id)),
+            }
+        }
+        find(&mut self.root, id)
+    }
+
+    /// Closes window `id`, which must not be the last one. Returns the
+    /// window that got its space, which becomes current if `id` was.
+    pub fn close(&mut self, id: usize) -> usize {
+        let next = self
+            .root
+            .remove(id)
+            .unwrap_or_else(|| self.root.first_leaf());
+        if self.current == id {
+            self.current = next;
+        }
+        if let Node::Split(_, children) = &mut self.root {
+            if children.len() == 1 {
+                let (only, _) = children.pop().unwrap();
+                self.root = only;
+            }
+        }
+        self.windows.retain(|w| w.id != id);
+        self.relayout();
+        next
+    }
+
+    /// Closes every window but the current one. Returns the closed ones.
+    pub fn only(&mut self) -> Vec<Window> {
+        let cur = self.current;
+        self.root = Node::Leaf(cur);
+        let (kept, closed) = std::mem::take(&mut self.windows)
+            .into_iter()
+            .partition(|w| w.id == cur);
+        self.windows = kept;
+        self.relayout();
+        closed
+    }
+
+    /// Makes the current window `delta` cells taller or wider (`Rows` or
+    /// `Columns`), taking the space from the windows after it and then the
+    /// ones before it, or giving it to the next one.
+    pub fn resize(&mut self, dir: Dir, delta: isize) {
+        let cur = self.current;
+        let Some((children, i)) = self.root.split_of(cur, dir) else {
+            return;
+        };
+        let mins: Vec<usize> = children.iter().map(|(c, _)| c.min_size(dir)).collect();
+        if delta < 0 {
+            let cut = delta
+                .unsigned_abs()
+                .min(children[i].1.saturating_sub(mins[i]));
+            let to = if i + 1 < children.len() { i + 1 } else { i - 1 };
+            children[i].1 -= cut;
+            children[to].1 += cut;
+        } else {
+            let mut want = delta as usize;
+            let order: Vec<usize> = (i + 1..children.len()).chain((0..i).rev()).collect();
+            for j in order {
+                let cut = want.min(children[j].1.saturating_sub(mins[j]));
+                children[j].1 -= cut;
+                children[i].1 += cut;
+                want -= cut;
+            }
+        }
+        self.relayout();
+    }
+
+    /// `Ctrl-w =`: makes the windows of every split the same size.
+    pub fn equalize(&mut self) {
+        fn even(node: &mut Node) {
+            if let Node::Split(_, children) = node {
+                let total: usize = children.iter().map(|(_, s)| s).sum();
+                let n = children.len();
+                for (i, (child, size)) in children.iter_mut().enumerate() {
+                    *size = total / n + usize::from(i < total % n);
+                    even(child);
+                }
+            }
+        }
+        even(&mut self.root);
+        self.relayout();
+    }
+
+    /// The window next to the current one in the direction of `key`
+    /// (`h`, `j`, `k` or `l`), the one beside screen position `(x, y)` where
+    /// there are several.
+    pub fn neighbour(&self, key: char, (x, y): (usize, usize)) -> Option<usize> {
+        let cur = self.current().rect;
+        let beside = |r: &Rect| match key {
+            'h' => r.x + r.width + 1 == cur.x && (r.y..r.y + r.height).contains(&y),
+            'l' => r.x == cur.x + cur.width + 1 && (r.y..r.y + r.height).contains(&y),
+            // The separator column goes with the window to its left
+            'k' => r.y + r.height == cur.y && (r.x..=r.x + r.width).contains(&x),
+            'j' => r.y == cur.y + cur.height && (r.x..=r.x + r.width).contains(&x),
+            _ => false,
+        };
+        self.windows
+            .iter()
+            .filter(|w| beside(&w.rect))
+            .min_by_key(|w| w.rect.x.abs_diff(x) + w.rect.y.abs_diff(y))
+            .map(|w| w.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rects(layout: &Layout) -> Vec<(usize, usize, usize, usize)> {
+        layout
+            .ids()
+            .iter()
+            .map(|&id| {
+                let r = layout.get(id).unwrap().rect;
+                (r.x, r.y, r.width, r.height)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_resize_and_close() {
+        let area = Rect {
+            x: 0,
+            y: 0,
+            width: 81,
+            height: 24,
+        };
+        let mut layout = Layout::default();
+        layout.layout(area);
+        let top = 2;
+        assert!(layout.split(Dir::Rows, top));
+        assert_eq!(rects(&layout), vec![(0, 0, 81, 12), (0, 12, 81, 12)]);
+
+        // The new window goes left of the current one, after a separator
+        layout.current = top;
+        let left = 3;
+        assert!(layout.split(Dir::Columns, left));
+        assert_eq!(
+            rects(&layout),
+            vec![(0, 0, 40, 12), (41, 0, 40, 12), (0, 12, 81, 12)]
+        );
+        layout.current = left;
+        assert_eq!(layout.neighbour('l', (3, 5)), Some(top));
+        assert_eq!(layout.neighbour('j', (3, 5)), Some(1));
+        layout.current = 1;
+        assert_eq!(layout.neighbour('k', (60, 15)), Some(top));
+        assert_eq!(layout.neighbour('j', (60, 15)), None);
+
+        layout.resize(Dir::Rows, 4);
+        layout.current = left;
+        layout.resize(Dir::Columns, -10);
+        assert_eq!(
+            rects(&layout),
+            vec![(0, 0, 30, 8), (31, 0, 50, 8), (0, 8, 81, 16)]
+        );
+        // Sizes keep their proportions on a smaller screen
+        layout.layout(Rect { height: 12, ..area });
+        assert_eq!(
+            rects(&layout),
+            vec![(0, 0, 30, 4), (31, 0, 50, 4), (0, 4, 81, 8)]
+        );
+        layout.equalize();
+        layout.layout(area);
+        assert_eq!(
+            rects(&layout),
+            vec![(0, 0, 40, 12), (41, 0, 40, 12), (0, 12, 81, 12)]
+        );
+
+        assert_eq!(layout.close(left), top);
+        assert_eq!(layout.current, top);
+        assert_eq!(rects(&layout), vec![(0, 0, 81, 12), (0, 12, 81, 12)]);
+        layout.current = 1;
+        let closed: Vec<usize> = layout.only().iter().map(|w| w.id).collect();
+        assert_eq!(closed, vec![top]);
+        assert_eq!(rects(&layout), vec![(0, 0, 81, 24)]);
+        layout.layout(Rect { height: 3, ..area });
+        assert!(!layout.split(Dir::Rows, 4));
+    }
+}